@@ -0,0 +1,311 @@
+//! One JSON line per proxied request, written to a size-rotated file by a dedicated thread
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::StreamExt;
+use http::{header::CONTENT_LENGTH, HeaderMap, HeaderValue};
+use hyper::Body;
+use log::{debug, info, warn};
+use once_cell::sync::OnceCell;
+use piam_proxy::type_alias::{HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    metrics,
+    response::{ErrorCode, X_AMZ_REQUEST_ID},
+    shutdown::{self, InFlight},
+    sigv4::civil_from_days,
+};
+
+/// Records waiting to be written, further records are dropped
+const ACCESS_LOG_QUEUE_SIZE: usize = 10_000;
+/// Headers and query parameters never written to any log
+const REDACTED: &[&str] = &[
+    "authorization",
+    "x-amz-security-token",
+    "x-amz-signature",
+    "x-amz-credential",
+];
+
+static SINK: OnceCell<SyncSender<AccessRecord>> = OnceCell::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessLogConfig {
+    pub path: String,
+    /// rotate once the file reaches this size
+    #[serde(default = "AccessLogConfig::default_max_size_mb")]
+    pub max_size_mb: u64,
+    /// rotated files kept as `{path}.1` to `{path}.{max_files}`
+    #[serde(default = "AccessLogConfig::default_max_files")]
+    pub max_files: usize,
+}
+
+impl AccessLogConfig {
+    fn default_max_size_mb() -> u64 {
+        100
+    }
+
+    fn default_max_files() -> usize {
+        10
+    }
+
+    /// Start the writer thread, records are only logged at debug level before this is called
+    pub fn init(&self) -> std::io::Result<()> {
+        let mut writer = RotatingFile::open(self)?;
+        let (sender, receiver) = sync_channel(ACCESS_LOG_QUEUE_SIZE);
+        if SINK.set(sender).is_err() {
+            warn!("access log already initialized");
+            return Ok(());
+        }
+        std::thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || writer.run(receiver))?;
+        info!("access log written to {}", self.path);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct AccessRecord {
+    pub time: String,
+    pub request_id: String,
+    pub client_addr: String,
+    pub method: String,
+    /// path and query, with the authentication parameters of presigned urls redacted
+    pub uri: String,
+    pub access_key: Option<String>,
+    pub account: Option<String>,
+    pub region: Option<String>,
+    pub bucket: Option<String>,
+    pub key: Option<String>,
+    pub action: Option<String>,
+    /// `allow` or `deny`, absent if the request failed before the policies were evaluated
+    pub decision: Option<&'static str>,
+    pub status: u16,
+    pub error_code: Option<String>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub latency_ms: u64,
+    #[serde(skip)]
+    started: Option<Instant>,
+    /// keeps the request in flight for the graceful shutdown until the record is emitted
+    #[serde(skip)]
+    in_flight: Option<Arc<InFlight>>,
+}
+
+impl AccessRecord {
+    pub fn start(req: &HttpRequest, addr: SocketAddr) -> Self {
+        let mut record = Self {
+            time: format_time(SystemTime::now()),
+            client_addr: addr.to_string(),
+            method: req.method().to_string(),
+            uri: redact_uri(req),
+            bytes_in: content_length(req.headers()),
+            started: Some(Instant::now()),
+            ..Default::default()
+        };
+        record.in_flight = Some(Arc::new(shutdown::track(&record)));
+        record
+    }
+
+    /// Complete the record with the response, it is written once the body has been sent
+    /// or the client went away
+    pub fn finish(mut self, mut res: HttpResponse) -> HttpResponse {
+        let request_id = match res.headers().get(X_AMZ_REQUEST_ID) {
+            Some(id) => id.to_str().unwrap_or_default().to_string(),
+            None => {
+                let id = Uuid::new_v4().simple().to_string().to_uppercase();
+                if let Ok(value) = HeaderValue::from_str(&id) {
+                    res.headers_mut().insert(X_AMZ_REQUEST_ID, value);
+                }
+                id
+            }
+        };
+        self.request_id = request_id;
+        self.status = res.status().as_u16();
+        self.error_code = res.extensions().get::<ErrorCode>().map(|c| c.0.to_string());
+
+        let (parts, body) = res.into_parts();
+        let mut pending = PendingRecord(self);
+        let body = body.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                pending.0.bytes_out += chunk.len() as u64;
+            }
+        });
+        HttpResponse::from_parts(parts, Body::wrap_stream(body))
+    }
+
+    fn emit(&mut self) {
+        self.in_flight = None;
+        self.latency_ms = self
+            .started
+            .map_or(0, |started| started.elapsed().as_millis() as u64);
+        metrics::observe_request(self);
+        match SINK.get() {
+            Some(sink) => match sink.try_send(std::mem::take(self)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(record)) => {
+                    warn!(
+                        "access log queue full, dropping record {}",
+                        record.request_id
+                    )
+                }
+                Err(TrySendError::Disconnected(_)) => warn!("access log writer stopped"),
+            },
+            None => debug!("access {}", serde_json::to_string(self).unwrap_or_default()),
+        }
+    }
+}
+
+/// Emits the record when the response body is dropped
+struct PendingRecord(AccessRecord);
+
+impl Drop for PendingRecord {
+    fn drop(&mut self) {
+        self.0.emit();
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    size: u64,
+    file: BufWriter<File>,
+}
+
+impl RotatingFile {
+    fn open(config: &AccessLogConfig) -> std::io::Result<Self> {
+        let path = PathBuf::from(&config.path);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            size: file.metadata()?.len(),
+            file: BufWriter::new(file),
+            path,
+            max_size: config.max_size_mb * 1024 * 1024,
+            max_files: config.max_files,
+        })
+    }
+
+    fn run(&mut self, receiver: Receiver<AccessRecord>) {
+        loop {
+            // flush while idle so records show up without waiting for the buffer to fill
+            let record = match receiver.recv_timeout(Duration::from_secs(1)) {
+                Ok(record) => record,
+                Err(RecvTimeoutError::Timeout) => {
+                    let _ = self.file.flush();
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Err(e) = self.write(&record) {
+                warn!("failed to write access log: {e}");
+            }
+        }
+        let _ = self.file.flush();
+    }
+
+    fn write(&mut self, record: &AccessRecord) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        for n in (1..self.max_files).rev() {
+            if rotated(n).exists() {
+                fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, rotated(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Headers safe to be logged
+pub fn redact_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    for name in REDACTED {
+        if let Some(value) = headers.get_mut(*name) {
+            *value = HeaderValue::from_static("<redacted>");
+        }
+    }
+    headers
+}
+
+fn redact_uri(req: &HttpRequest) -> String {
+    let path = req.uri().path();
+    let Some(query) = req.uri().query() else {
+        return path.to_string();
+    };
+    let query: Vec<String> = query
+        .split('&')
+        .map(|pair| {
+            let name = pair.split('=').next().unwrap_or_default();
+            if REDACTED.contains(&name.to_ascii_lowercase().as_str()) {
+                format!("{name}=<redacted>")
+            } else {
+                pair.to_string()
+            }
+        })
+        .collect();
+    format!("{}?{}", path, query.join("&"))
+}
+
+fn content_length(headers: &HeaderMap) -> u64 {
+    // streaming uploads carry the length of the payload without the chunk signatures
+    headers
+        .get("x-amz-decoded-content-length")
+        .or_else(|| headers.get(CONTENT_LENGTH))
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default()
+}
+
+/// RFC 3339 time in UTC with milliseconds
+fn format_time(time: SystemTime) -> String {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64);
+    let (unix, millis) = (millis.div_euclid(1000), millis.rem_euclid(1000));
+    let (days, seconds) = (unix.div_euclid(86400), unix.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        millis
+    )
+}
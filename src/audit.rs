@@ -0,0 +1,212 @@
+//! Audit trail of policy decisions, appended as JSON lines to a local file.
+//! Every event carries the hash of the previous one, so a removed or edited line breaks the
+//! chain and is detected by [`verify`].
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::SocketAddr,
+    sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+    time::SystemTime,
+};
+
+use log::{debug, info, warn};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    metrics,
+    sigv4::{format_amz_date, sha256_hex},
+};
+
+/// `prev_hash` of the first event of a trail
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Events waiting to be written, further events are dropped and counted rather than slowing
+/// down or piling up requests while the disk lags behind
+const AUDIT_LOG_QUEUE_SIZE: usize = 10_000;
+/// Events written at most before the file is synced
+const AUDIT_SYNC_BATCH: usize = 256;
+
+static SINK: OnceCell<SyncSender<AuditEvent>> = OnceCell::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditLogConfig {
+    pub path: String,
+}
+
+impl AuditLogConfig {
+    /// Start the writer thread, continuing the chain of the events already in the file
+    pub fn init(&self) -> std::io::Result<()> {
+        let last_hash = last_hash(&self.path)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let (sender, receiver) = sync_channel(AUDIT_LOG_QUEUE_SIZE);
+        if SINK.set(sender).is_err() {
+            warn!("audit log already initialized");
+            return Ok(());
+        }
+        std::thread::Builder::new()
+            .name("audit-log".into())
+            .spawn(move || write_chain(file, last_hash, receiver))?;
+        info!("audit log written to {}", self.path);
+        Ok(())
+    }
+}
+
+/// Who tried to do what, the policies found for it and the resulting decision
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub time: String,
+    pub client_addr: String,
+    pub access_key: String,
+    pub account: String,
+    pub region: String,
+    pub action: String,
+    pub bucket: String,
+    pub key: Option<String>,
+    /// ids of the policies found for the user, account and region
+    pub matched_policies: Vec<String>,
+    pub condition_effects: Option<String>,
+    pub user_input_effects: Option<String>,
+    /// `allow` or `deny`
+    pub decision: String,
+    pub reason: Option<String>,
+    pub prev_hash: String,
+    pub hash: String,
+    /// events of synthetic requests, e.g. the probes of ListBuckets, are not written
+    #[serde(skip)]
+    enabled: bool,
+}
+
+impl AuditEvent {
+    pub fn new(
+        addr: SocketAddr,
+        access_key: &str,
+        action: String,
+        bucket: &str,
+        key: Option<String>,
+    ) -> Self {
+        Self {
+            client_addr: addr.to_string(),
+            access_key: access_key.to_string(),
+            action,
+            bucket: bucket.to_string(),
+            key,
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn allow(&mut self) {
+        self.decision = "allow".into();
+        self.emit();
+    }
+
+    pub fn deny(&mut self, reason: String) {
+        self.decision = "deny".into();
+        self.reason = Some(reason);
+        self.emit();
+    }
+
+    fn emit(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        self.enabled = false;
+        self.time = format_amz_date(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64),
+        );
+        match SINK.get() {
+            Some(sink) => match sink.try_send(self.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(event)) => {
+                    metrics::observe_audit_event_dropped();
+                    warn!("audit log queue full, event lost: {:?}", event);
+                }
+                Err(TrySendError::Disconnected(event)) => {
+                    warn!("audit log writer stopped, event lost: {:?}", event)
+                }
+            },
+            None => debug!("audit {}", serde_json::to_string(self).unwrap_or_default()),
+        }
+    }
+
+    /// Hash of the event with its `prev_hash`, `hash` itself excluded
+    fn compute_hash(&self) -> String {
+        let mut event = serde_json::to_value(self).unwrap_or_default();
+        if let Value::Object(fields) = &mut event {
+            fields.remove("hash");
+        }
+        sha256_hex(event.to_string().as_bytes())
+    }
+}
+
+fn write_chain(file: File, mut last_hash: String, receiver: Receiver<AuditEvent>) {
+    let mut file = BufWriter::new(file);
+    while let Ok(event) = receiver.recv() {
+        // events queued meanwhile are written along and synced at once
+        let batch = std::iter::once(event).chain(receiver.try_iter().take(AUDIT_SYNC_BATCH - 1));
+        let mut written = 0;
+        for mut event in batch {
+            event.prev_hash = last_hash.clone();
+            event.hash = event.compute_hash();
+            let line = serde_json::to_string(&event).unwrap_or_default();
+            match writeln!(file, "{line}") {
+                Ok(()) => last_hash = event.hash,
+                Err(e) => warn!("failed to write audit event {line}: {e}"),
+            }
+            written += 1;
+        }
+        if let Err(e) = file.flush().and_then(|_| file.get_ref().sync_data()) {
+            warn!("failed to sync {written} audit events: {e}");
+        }
+    }
+}
+
+fn last_hash(path: &str) -> std::io::Result<String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(GENESIS_HASH.into()),
+        Err(e) => return Err(e),
+    };
+    let mut last_hash = GENESIS_HASH.to_string();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Ok(event) = serde_json::from_str::<AuditEvent>(&line) {
+            last_hash = event.hash;
+        }
+    }
+    Ok(last_hash)
+}
+
+/// Check the hash chain of an audit trail, returns the number of events or the line number
+/// (starting from 1) of the first event breaking the chain
+pub fn verify(path: &str) -> std::io::Result<Result<usize, usize>> {
+    let file = File::open(path)?;
+    let mut prev_hash = GENESIS_HASH.to_string();
+    let mut count = 0;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Ok(event) = serde_json::from_str::<AuditEvent>(&line) else {
+            return Ok(Err(index + 1));
+        };
+        if event.prev_hash != prev_hash || event.compute_hash() != event.hash {
+            return Ok(Err(index + 1));
+        }
+        prev_hash = event.hash;
+        count += 1;
+    }
+    Ok(Ok(count))
+}
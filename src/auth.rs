@@ -0,0 +1,382 @@
+//! Verification of the SigV4 signature of client requests with the secret key of the user,
+//! either from the `Authorization` header or from the query of a presigned url
+//! <https://docs.aws.amazon.com/AmazonS3/latest/API/sig-v4-authenticating-requests.html>
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use busylib::prelude::EnhancedUnwrap;
+use http::{
+    header::{AUTHORIZATION, HOST},
+    Method, Request, StatusCode,
+};
+use hyper::Body;
+use piam_object_storage::policy::ObjectStoragePolicy;
+use piam_proxy::{container::IamContainer, error::ProxyResult, type_alias::HttpRequest};
+
+use crate::{
+    config::SERVICE,
+    response::S3Error,
+    sigv4::{
+        format_amz_date, hmac_sha256, parse_amz_date, percent_decode, sha256_hex, signature_eq,
+        uri_encode, Authorization, Scope, ALGORITHM, X_AMZ_CONTENT_SHA256, X_AMZ_DATE,
+    },
+};
+
+/// Maximum difference in seconds between the request time and the clock of the proxy
+pub const MAX_CLOCK_SKEW: i64 = 15 * 60;
+/// Maximum X-Amz-Expires of a presigned url, 7 days
+pub const MAX_PRESIGNED_EXPIRES: i64 = 7 * 24 * 3600;
+pub const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+pub const X_AMZ_SIGNATURE: &str = "X-Amz-Signature";
+
+/// SigV4 authentication information carried by a request
+#[derive(Clone, Debug)]
+pub struct SigV4Auth {
+    pub access_key: String,
+    pub scope: Scope,
+    pub signed_headers: Vec<String>,
+    pub signature: String,
+    pub timestamp: String,
+    /// Seconds the presigned url is valid for, `None` for header-signed requests
+    pub expires: Option<i64>,
+}
+
+impl SigV4Auth {
+    pub fn from_request(req: &HttpRequest) -> Result<Self, S3Error> {
+        if let Some(authorization) = req.headers().get(AUTHORIZATION) {
+            let authorization = authorization
+                .to_str()
+                .ok()
+                .and_then(|a| Authorization::parse(a).ok())
+                .ok_or_else(|| {
+                    S3Error::new(
+                        StatusCode::BAD_REQUEST,
+                        "AuthorizationHeaderMalformed",
+                        "the authorization header is not a valid AWS Signature Version 4 header",
+                    )
+                })?;
+            let timestamp = req
+                .headers()
+                .get(X_AMZ_DATE)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| S3Error::access_denied("x-amz-date should be present"))?;
+            if !signs_host(&authorization.signed_headers) {
+                return Err(S3Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "AuthorizationHeaderMalformed",
+                    "host should be one of the signed headers",
+                ));
+            }
+            return Ok(Self {
+                access_key: authorization.access_key,
+                scope: authorization.scope,
+                signed_headers: authorization.signed_headers,
+                signature: authorization.signature,
+                timestamp: timestamp.to_string(),
+                expires: None,
+            });
+        }
+
+        let query = query_params(req);
+        let param = |name: &str| {
+            query
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+                .ok_or_else(|| malformed_query(&format!("{name} should be present")))
+        };
+        match param("X-Amz-Algorithm") {
+            Ok(ALGORITHM) => {}
+            Ok(_) => {
+                return Err(malformed_query(
+                    "X-Amz-Algorithm should be AWS4-HMAC-SHA256",
+                ))
+            }
+            Err(_) => {
+                return Err(S3Error::access_denied(
+                    "request should be signed with AWS Signature Version 4",
+                ))
+            }
+        }
+        let (access_key, scope) = param("X-Amz-Credential")?
+            .split_once('/')
+            .ok_or_else(|| malformed_query("X-Amz-Credential not valid"))?;
+        let expires = param("X-Amz-Expires")?
+            .parse::<i64>()
+            .ok()
+            .filter(|expires| (1..=MAX_PRESIGNED_EXPIRES).contains(expires))
+            .ok_or_else(|| {
+                malformed_query(&format!(
+                    "X-Amz-Expires should be between 1 and {MAX_PRESIGNED_EXPIRES}"
+                ))
+            })?;
+        let signed_headers: Vec<String> = param("X-Amz-SignedHeaders")?
+            .split(';')
+            .map(|h| h.to_string())
+            .collect();
+        if !signs_host(&signed_headers) {
+            return Err(malformed_query("host should be one of X-Amz-SignedHeaders"));
+        }
+        Ok(Self {
+            access_key: access_key.to_string(),
+            scope: Scope::parse(scope)
+                .map_err(|_| malformed_query("X-Amz-Credential not valid"))?,
+            signed_headers,
+            signature: param(X_AMZ_SIGNATURE)?.to_string(),
+            timestamp: param("X-Amz-Date")?.to_string(),
+            expires: Some(expires),
+        })
+    }
+
+    pub fn is_presigned(&self) -> bool {
+        self.expires.is_some()
+    }
+}
+
+/// SigV4 requires the host to be signed, otherwise a signed request could be replayed against
+/// another virtual-hosted bucket
+fn signs_host(signed_headers: &[String]) -> bool {
+    signed_headers.iter().any(|name| name == "host")
+}
+
+/// Verify the signature of the request with the secret key of the user it claims to be
+pub fn verify(
+    iam_container: &IamContainer<ObjectStoragePolicy>,
+    req: &HttpRequest,
+) -> Result<SigV4Auth, S3Error> {
+    let auth = SigV4Auth::from_request(req)?;
+    let secret_key = user_secret_key(iam_container, &auth.access_key).map_err(|_| {
+        S3Error::new(
+            StatusCode::FORBIDDEN,
+            "InvalidAccessKeyId",
+            "the access key id you provided does not exist in our records",
+        )
+    })?;
+    verify_signature(req, &auth, secret_key, unix_now())?;
+    Ok(auth)
+}
+
+pub fn user_secret_key<'a>(
+    iam_container: &'a IamContainer<ObjectStoragePolicy>,
+    access_key: &str,
+) -> ProxyResult<&'a str> {
+    let user = iam_container.find_user_by_base_access_key(base_access_key(access_key)?)?;
+    Ok(&user.secret_key)
+}
+
+pub fn user_id<'a>(
+    iam_container: &'a IamContainer<ObjectStoragePolicy>,
+    access_key: &str,
+) -> ProxyResult<&'a str> {
+    let user = iam_container.find_user_by_base_access_key(base_access_key(access_key)?)?;
+    Ok(&user.id)
+}
+
+fn base_access_key(access_key: &str) -> ProxyResult<&str> {
+    // When feature uni-key is enabled, base_access_key is aws access_key,
+    // otherwise base_access_key + account_code = aws_access_key
+    #[cfg(feature = "uni-key")]
+    let base_access_key = access_key;
+    #[cfg(not(feature = "uni-key"))]
+    let (base_access_key, _) = piam_proxy::signature::split_to_base_and_account_code(access_key)?;
+    Ok(base_access_key)
+}
+
+/// Check the request time against `now` (unix seconds), then the signature
+pub fn verify_signature(
+    req: &HttpRequest,
+    auth: &SigV4Auth,
+    secret_key: &str,
+    now: i64,
+) -> Result<(), S3Error> {
+    let time = parse_amz_date(&auth.timestamp)
+        .filter(|_| auth.timestamp.starts_with(&auth.scope.date))
+        .ok_or_else(|| S3Error::access_denied("request date not valid"))?;
+    match auth.expires {
+        None if (now - time).abs() > MAX_CLOCK_SKEW => {
+            return Err(S3Error::new(
+                StatusCode::FORBIDDEN,
+                "RequestTimeTooSkewed",
+                "the difference between the request time and the current time is too large",
+            ))
+        }
+        Some(_) if time - now > MAX_CLOCK_SKEW => {
+            return Err(S3Error::access_denied("request is not valid yet"))
+        }
+        Some(expires) if now > time + expires => {
+            return Err(S3Error::access_denied("request has expired"))
+        }
+        _ => {}
+    }
+
+    let signature = compute_signature(req, auth, secret_key)?;
+    if !signature_eq(&signature, &auth.signature) {
+        return Err(S3Error::new(
+            StatusCode::FORBIDDEN,
+            "SignatureDoesNotMatch",
+            "the request signature we calculated does not match the signature you provided",
+        ));
+    }
+    Ok(())
+}
+
+/// Presign a request to the proxy, returns the path and query of the presigned url.
+/// Only the host header is signed and the payload is left unsigned.
+#[allow(clippy::too_many_arguments)]
+pub fn presign(
+    method: &Method,
+    host: &str,
+    path: &str,
+    access_key: &str,
+    secret_key: &str,
+    region: &str,
+    expires: i64,
+    now: i64,
+) -> Result<String, S3Error> {
+    let timestamp = format_amz_date(now);
+    let auth = SigV4Auth {
+        access_key: access_key.to_string(),
+        scope: Scope {
+            date: timestamp[..8].to_string(),
+            region: region.to_string(),
+            service: SERVICE.to_string(),
+        },
+        signed_headers: vec!["host".to_string()],
+        signature: String::new(),
+        timestamp,
+        expires: Some(expires),
+    };
+    let path_and_query = format!(
+        "{}?X-Amz-Algorithm={}&X-Amz-Credential={}&X-Amz-Date={}&X-Amz-Expires={}\
+         &X-Amz-SignedHeaders=host",
+        uri_encode(path, true),
+        ALGORITHM,
+        uri_encode(&format!("{}/{}", auth.access_key, auth.scope), false),
+        auth.timestamp,
+        expires
+    );
+    let req = Request::builder()
+        .method(method.clone())
+        .uri(&path_and_query)
+        .header(HOST, host)
+        .body(Body::empty())
+        .map_err(|e| {
+            S3Error::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidArgument",
+                format!("request to presign not valid: {e}"),
+            )
+        })?;
+    let signature = compute_signature(&req, &auth, secret_key)?;
+    Ok(format!("{path_and_query}&{X_AMZ_SIGNATURE}={signature}"))
+}
+
+fn compute_signature(
+    req: &HttpRequest,
+    auth: &SigV4Auth,
+    secret_key: &str,
+) -> Result<String, S3Error> {
+    let canonical_request = canonical_request(req, auth)?;
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        auth.timestamp,
+        auth.scope,
+        sha256_hex(canonical_request.as_bytes())
+    );
+    Ok(hex::encode(hmac_sha256(
+        &auth.scope.signing_key(secret_key),
+        string_to_sign.as_bytes(),
+    )))
+}
+
+fn canonical_request(req: &HttpRequest, auth: &SigV4Auth) -> Result<String, S3Error> {
+    let uri = uri_encode(&percent_decode(req.uri().path()), true);
+
+    let mut query: Vec<(String, String)> = query_params(req)
+        .into_iter()
+        .filter(|(k, _)| !(auth.is_presigned() && k == X_AMZ_SIGNATURE))
+        .map(|(k, v)| (uri_encode(&k, false), uri_encode(&v, false)))
+        .collect();
+    query.sort();
+    let query = query
+        .iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let mut headers = String::new();
+    for name in &auth.signed_headers {
+        let mut values: Vec<String> = req
+            .headers()
+            .get_all(name.as_str())
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(|v| v.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect();
+        if values.is_empty() && name == "host" {
+            values.extend(req.uri().authority().map(|a| a.to_string()));
+        }
+        if values.is_empty() {
+            return Err(S3Error::access_denied(format!(
+                "signed header {name} should be present"
+            )));
+        }
+        headers.push_str(&format!("{}:{}\n", name, values.join(",")));
+    }
+
+    let payload_hash = if auth.is_presigned() {
+        UNSIGNED_PAYLOAD
+    } else {
+        req.headers()
+            .get(X_AMZ_CONTENT_SHA256)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| {
+                S3Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "InvalidRequest",
+                    "x-amz-content-sha256 should be present",
+                )
+            })?
+    };
+
+    Ok(format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        req.method(),
+        uri,
+        query,
+        headers,
+        auth.signed_headers.join(";"),
+        payload_hash
+    ))
+}
+
+/// Decoded query parameters in their original order
+pub fn query_params(req: &HttpRequest) -> Vec<(String, String)> {
+    req.uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(k), percent_decode(v))
+        })
+        .collect()
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwp()
+        .as_secs() as i64
+}
+
+#[inline]
+fn malformed_query(message: &str) -> S3Error {
+    S3Error::new(
+        StatusCode::BAD_REQUEST,
+        "AuthorizationQueryParametersError",
+        message,
+    )
+}
@@ -0,0 +1,215 @@
+//! Re-signing of streaming uploads (`aws-chunked` content encoding)
+//! <https://docs.aws.amazon.com/AmazonS3/latest/API/sigv4-streaming.html>
+//!
+//! Every chunk signature is chained to the previous one, starting from the seed signature in the
+//! `Authorization` header. Once the headers are re-signed for the target account, the chunks are
+//! verified with the key of the user and re-signed with the key of the account one at a time, so
+//! the body is streamed through without being buffered.
+
+use std::io;
+
+use bytes::{Bytes, BytesMut};
+use http::header::AUTHORIZATION;
+use hyper::{body::HttpBody, Body};
+use piam_proxy::{
+    error::{ProxyError, ProxyResult},
+    type_alias::HttpRequest,
+};
+
+use crate::sigv4::{
+    hmac_sha256, sha256_hex, signature_eq, Authorization, Scope, EMPTY_SHA256,
+    X_AMZ_CONTENT_SHA256, X_AMZ_DATE,
+};
+
+pub const STREAMING_PAYLOAD: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD";
+const CHUNK_SIGNATURE: &str = ";chunk-signature=";
+/// `<hex size>;chunk-signature=<64 hex>` is far shorter than this
+const MAX_CHUNK_HEADER_LEN: usize = 1024;
+/// A whole chunk is held in memory before being re-signed
+const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+pub fn is_streaming(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(X_AMZ_CONTENT_SHA256)
+        .map_or(false, |v| v == STREAMING_PAYLOAD)
+}
+
+/// Streaming uploads other than [`STREAMING_PAYLOAD`] can not be re-signed, e.g. the trailer
+/// variants sent for checksums, forwarding them would fail upstream with a broken signature
+pub fn check_supported(req: &HttpRequest) -> ProxyResult<()> {
+    let Some(content_sha256) = req.headers().get(X_AMZ_CONTENT_SHA256) else {
+        return Ok(());
+    };
+    let content_sha256 = content_sha256.to_str().unwrap_or_default();
+    if content_sha256.starts_with("STREAMING-") && content_sha256 != STREAMING_PAYLOAD {
+        return Err(ProxyError::OperationNotSupported(format!(
+            "{X_AMZ_CONTENT_SHA256} {content_sha256} not supported, \
+             use {STREAMING_PAYLOAD} or a signed payload"
+        )));
+    }
+    Ok(())
+}
+
+/// Computes the chained signatures of the chunks
+#[derive(Clone, Debug)]
+pub struct ChunkSigner {
+    signing_key: Vec<u8>,
+    timestamp: String,
+    scope: String,
+    previous_signature: String,
+}
+
+impl ChunkSigner {
+    pub fn new(signing_key: Vec<u8>, timestamp: &str, scope: &Scope, seed_signature: &str) -> Self {
+        Self {
+            signing_key,
+            timestamp: timestamp.to_string(),
+            scope: scope.to_string(),
+            previous_signature: seed_signature.to_string(),
+        }
+    }
+
+    /// Take the seed signature, scope and timestamp from a header-signed request
+    pub fn from_seed(req: &HttpRequest, secret_key: &str) -> ProxyResult<Self> {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| {
+                    ProxyError::MalformedProtocol(format!(
+                        "{name} should be present for streaming upload"
+                    ))
+                })
+        };
+        let authorization = Authorization::parse(header(AUTHORIZATION.as_str())?)?;
+        Ok(Self::new(
+            authorization.scope.signing_key(secret_key),
+            header(X_AMZ_DATE)?,
+            &authorization.scope,
+            &authorization.signature,
+        ))
+    }
+
+    /// Signature of the next chunk, the final chunk has empty data
+    pub fn sign(&mut self, data: &[u8]) -> String {
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256-PAYLOAD\n{}\n{}\n{}\n{}\n{}",
+            self.timestamp,
+            self.scope,
+            self.previous_signature,
+            EMPTY_SHA256,
+            sha256_hex(data)
+        );
+        let signature = hex::encode(hmac_sha256(&self.signing_key, string_to_sign.as_bytes()));
+        self.previous_signature = signature.clone();
+        signature
+    }
+}
+
+/// Verify every chunk of the body with `verifier`, then re-sign it with `signer`.
+/// The chunk size is kept as is, so the content length does not change.
+pub fn resign_body(req: HttpRequest, verifier: ChunkSigner, signer: ChunkSigner) -> HttpRequest {
+    let (parts, body) = req.into_parts();
+    let state = Rechunker {
+        body,
+        buf: BytesMut::new(),
+        verifier,
+        signer,
+        done: false,
+    };
+    let stream = futures::stream::try_unfold(state, |mut state| async move {
+        loop {
+            if state.done {
+                return Ok(None);
+            }
+            if let Some(chunk) = state.next_chunk()? {
+                if state.done {
+                    state.expect_end().await?;
+                }
+                return Ok(Some((chunk, state)));
+            }
+            match state.body.data().await {
+                Some(data) => {
+                    let data = data.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    state.buf.extend_from_slice(&data);
+                }
+                None => return Err(invalid_data("body ended before the final chunk")),
+            }
+        }
+    });
+    HttpRequest::from_parts(parts, Body::wrap_stream(stream))
+}
+
+struct Rechunker {
+    body: Body,
+    buf: BytesMut,
+    verifier: ChunkSigner,
+    signer: ChunkSigner,
+    done: bool,
+}
+
+impl Rechunker {
+    /// Take a complete chunk from the buffer and re-sign it, `None` if more data is needed
+    fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        let Some(header_len) = self.buf.windows(2).position(|w| w == b"\r\n") else {
+            if self.buf.len() > MAX_CHUNK_HEADER_LEN {
+                return Err(invalid_data("chunk header too long"));
+            }
+            return Ok(None);
+        };
+        let header = std::str::from_utf8(&self.buf[..header_len])
+            .map_err(|_| invalid_data("chunk header should be utf-8"))?;
+        let (size_hex, signature) = header
+            .split_once(CHUNK_SIGNATURE)
+            .ok_or_else(|| invalid_data("chunk header should contain chunk-signature"))?;
+        let size = usize::from_str_radix(size_hex, 16)
+            .map_err(|_| invalid_data("chunk size should be hex"))?;
+        if size > MAX_CHUNK_SIZE {
+            return Err(invalid_data("chunk size too large"));
+        }
+        let data_start = header_len + 2;
+        let chunk_len = data_start + size + 2;
+        if self.buf.len() < chunk_len {
+            return Ok(None);
+        }
+        let size_hex = size_hex.to_string();
+        let signature = signature.to_string();
+
+        let chunk = self.buf.split_to(chunk_len);
+        if &chunk[chunk_len - 2..] != b"\r\n" {
+            return Err(invalid_data("chunk data should end with CRLF"));
+        }
+        let data = &chunk[data_start..data_start + size];
+        if !signature_eq(&self.verifier.sign(data), &signature) {
+            return Err(invalid_data("chunk signature does not match"));
+        }
+
+        let mut resigned = BytesMut::with_capacity(chunk_len);
+        resigned.extend_from_slice(size_hex.as_bytes());
+        resigned.extend_from_slice(CHUNK_SIGNATURE.as_bytes());
+        resigned.extend_from_slice(self.signer.sign(data).as_bytes());
+        resigned.extend_from_slice(b"\r\n");
+        resigned.extend_from_slice(data);
+        resigned.extend_from_slice(b"\r\n");
+        self.done = size == 0;
+        Ok(Some(resigned.freeze()))
+    }
+
+    /// Nothing may follow the final chunk, trailers included
+    async fn expect_end(&mut self) -> io::Result<()> {
+        let mut left = !self.buf.is_empty();
+        while let Some(data) = self.body.data().await {
+            let data = data.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            left |= !data.is_empty();
+        }
+        if left {
+            return Err(invalid_data("body should end with the final chunk"));
+        }
+        Ok(())
+    }
+}
+
+#[inline]
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
@@ -1,34 +1,135 @@
+use std::{collections::HashMap, net::IpAddr};
+
 use async_trait::async_trait;
 use busylib::config::dev_mode;
+use log::warn;
 use piam_object_storage::{config::HostDomains, policy::ObjectStoragePolicy};
 use piam_proxy::{
     config::CoreConfig,
     error::{ProxyError, ProxyResult},
+    request::from_region_to_host,
     state::ExtendedState,
 };
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    access_log::AccessLogConfig,
+    audit::AuditLogConfig,
+    shadow::{ShadowConfig, ShadowPolicies},
+    telemetry::TracingConfig,
+    tls::TlsConfig,
+    upstream::{shared_client, UpstreamClient, UpstreamScheme},
+};
+
 pub const CONFIG_FETCHING_TIMEOUT: u64 = 10;
 pub const DEV_PROXY_HOST: &str = "s3-proxy.dev";
 pub const SERVICE: &str = "s3";
+/// seconds before the first retry of a failed state refresh, doubled on every further failure
+pub const STATE_REFRESH_RETRY_DELAY: u64 = 5;
+/// upper bound in seconds of the delay between retries of a failing state refresh
+pub const STATE_REFRESH_MAX_BACKOFF: u64 = 300;
+/// seconds after the last successful load the state is reported stale, and `/health` degraded
+pub const STATE_STALE_AFTER: u64 = 900;
+/// seconds between two checks of the tls certificate files for changes
+pub const TLS_RELOAD_CHECK_INTERVAL: u64 = 10;
+/// seconds between two checks of the files of the local config for changes
+pub const LOCAL_CONFIG_CHECK_INTERVAL: u64 = 5;
+/// seconds the requests in flight are given to complete on shutdown
+pub const SHUTDOWN_DEADLINE: u64 = 300;
+#[cfg(feature = "uni-key")]
+pub const UNI_KEY_REFRESH_INTERVAL: u64 = 300;
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct S3Config {
     pub proxy_hosts: HostDomains,
+    /// bearer token of the management API, which is disabled outside dev mode when absent
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// region to upstream host, overrides the default host of the region,
+    /// e.g. private endpoints or a local S3 stand-in
+    #[serde(default)]
+    pub upstream_hosts: HashMap<String, String>,
+    /// scheme used to reach upstream, https unless overridden for the region
+    #[serde(default)]
+    pub upstream_scheme: UpstreamScheme,
+    /// region to upstream scheme, overrides `upstream_scheme`
+    #[serde(default)]
+    pub upstream_schemes: HashMap<String, UpstreamScheme>,
+    /// path of a PEM bundle of extra CA certificates trusted for upstream,
+    /// e.g. private endpoints behind an internal CA, a rotated bundle is picked up by the next
+    /// state refresh
+    #[serde(default)]
+    pub upstream_ca_bundle: Option<String>,
+    #[serde(skip)]
+    pub upstream_client: Option<UpstreamClient>,
+    /// readiness also requires a connection to the upstream host of every region in use: the
+    /// regions of `upstream_hosts`, of the uni-key accounts and the ones requests were
+    /// forwarded to
+    #[serde(default)]
+    pub probe_upstream: bool,
+    /// https listener of the proxy, plain http only when absent
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// addresses of the reverse proxies in front of the proxy, only their `x-forwarded-proto`
+    /// is honored, e.g. for the scheme of issued presigned urls
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// file of the access log, records go to the debug log when absent.
+    /// Read once at start, a change is only applied by a restart
+    #[serde(default)]
+    pub access_log: Option<AccessLogConfig>,
+    /// export of the spans of the request pipeline, disabled when absent.
+    /// Read once at start, a change is only applied by a restart
+    #[serde(default)]
+    pub tracing: Option<TracingConfig>,
+    /// hash-chained trail of policy decisions, events go to the debug log when absent.
+    /// Read once at start, a change is only applied by a restart
+    #[serde(default)]
+    pub audit_log: Option<AuditLogConfig>,
+    /// candidate policies evaluated next to the live ones without being enforced
+    #[serde(default)]
+    pub shadow: Option<ShadowConfig>,
+    #[serde(skip)]
+    pub shadow_policies: Option<ShadowPolicies>,
+    /// seconds the requests in flight are given to complete on shutdown, e.g. large uploads,
+    /// defaults to [`SHUTDOWN_DEADLINE`]
+    #[serde(default)]
+    pub shutdown_deadline: Option<u64>,
+    /// users of the core config, no request can be authorized without any
+    #[serde(skip)]
+    pub user_count: usize,
     #[cfg(feature = "uni-key")]
     pub uni_key_info: Option<crate::uni_key::UniKeyInfo>,
+    /// falls back to [`crate::uni_key::AccountRoute::defaults`] when empty
+    #[cfg(feature = "uni-key")]
+    #[serde(default)]
+    pub uni_key_routes: Vec<crate::uni_key::AccountRoute>,
+    /// seconds between two listings of the buckets of every account,
+    /// defaults to [`UNI_KEY_REFRESH_INTERVAL`]
+    #[cfg(feature = "uni-key")]
+    #[serde(default)]
+    pub uni_key_refresh_interval: Option<u64>,
+    /// account code to buckets, used instead of listing the buckets of every account,
+    /// e.g. for offline deployments
+    #[cfg(feature = "uni-key")]
+    #[serde(default)]
+    pub uni_key_static_buckets: Option<HashMap<String, Vec<String>>>,
 }
 
 #[async_trait]
 impl ExtendedState<S3Config, ObjectStoragePolicy> for S3Config {
     fn new_from(mut extended_config: S3Config) -> ProxyResult<Self> {
-        if dev_mode() {
-            extended_config
-                .proxy_hosts
-                .domains
-                .push(DEV_PROXY_HOST.to_string());
+        let domains = &mut extended_config.proxy_hosts.domains;
+        if dev_mode() && !domains.iter().any(|domain| domain == DEV_PROXY_HOST) {
+            domains.push(DEV_PROXY_HOST.to_string());
         }
-        // TODO: check HostDomains, any string in the list should not be a substring of others
+        // a refused config fails the refresh, the last good state keeps serving
+        validate_proxy_hosts(&extended_config.proxy_hosts.domains)?;
+        extended_config.upstream_client = Some(shared_client(
+            extended_config.upstream_ca_bundle.as_deref(),
+        )?);
+        #[cfg(feature = "uni-key")]
+        crate::uni_key::AccountRoute::validate(&extended_config.uni_key_routes)?;
         Ok(extended_config)
     }
 
@@ -36,18 +137,80 @@ impl ExtendedState<S3Config, ObjectStoragePolicy> for S3Config {
         mut self,
         core_config: &CoreConfig<ObjectStoragePolicy>,
     ) -> ProxyResult<Self> {
+        self.user_count = core_config.users.len();
+        self.shadow_policies = ShadowPolicies::load_or_disable(self.shadow.as_ref()).await;
         #[cfg(feature = "uni-key")]
         {
-            self.uni_key_info =
-                Some(crate::uni_key::UniKeyInfo::new_from(&core_config.accounts).await?);
-            return Ok(self);
-        };
-        #[cfg(not(feature = "uni-key"))]
+            let refresh_interval = std::time::Duration::from_secs(
+                self.uni_key_refresh_interval
+                    .unwrap_or(UNI_KEY_REFRESH_INTERVAL),
+            );
+            let uni_key_info = match &self.uni_key_static_buckets {
+                Some(buckets) => crate::uni_key::UniKeyInfo::new_static(
+                    &core_config.accounts,
+                    &self.uni_key_routes,
+                    buckets,
+                )?,
+                None => {
+                    crate::uni_key::UniKeyInfo::new_from(
+                        &core_config.accounts,
+                        &self.uni_key_routes,
+                        refresh_interval,
+                    )
+                    .await?
+                }
+            };
+            self.uni_key_info = Some(uni_key_info);
+        }
+        crate::refresh::record_build();
         Ok(self)
     }
 }
 
 impl S3Config {
+    pub fn upstream_host(&self, region: &str) -> ProxyResult<String> {
+        match self.upstream_hosts.get(region) {
+            Some(host) => Ok(host.clone()),
+            None => from_region_to_host(region).map(|host| host.to_string()),
+        }
+    }
+
+    pub fn upstream_scheme(&self, region: &str) -> UpstreamScheme {
+        self.upstream_schemes
+            .get(region)
+            .copied()
+            .unwrap_or(self.upstream_scheme)
+    }
+
+    pub fn get_upstream_client(&self) -> ProxyResult<&UpstreamClient> {
+        self.upstream_client
+            .as_ref()
+            .ok_or_else(|| ProxyError::AssertFail("upstream client not found".into()))
+    }
+
+    /// Settings of the sinks set up once at start, changed in `new`. They are kept as they are
+    /// by a state refresh and only applied by a restart.
+    pub fn unapplied_changes(&self, new: &S3Config) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.access_log != new.access_log {
+            changed.push("access_log");
+        }
+        if self.tracing != new.tracing {
+            changed.push("tracing");
+        }
+        if self.audit_log != new.audit_log {
+            changed.push("audit_log");
+        }
+        changed
+    }
+
+    /// Warn about the changes of `new` a state refresh does not apply
+    pub fn warn_unapplied_changes(&self, new: &S3Config) {
+        for name in self.unapplied_changes(new) {
+            warn!("{name} changed, the change is only applied by a restart");
+        }
+    }
+
     #[cfg(feature = "uni-key")]
     pub fn get_uni_key_info(&self) -> ProxyResult<&crate::uni_key::UniKeyInfo> {
         self.uni_key_info
@@ -56,6 +219,75 @@ impl S3Config {
     }
 }
 
+/// Proxy hosts must be valid host names, optionally with a port. None of them may be a
+/// substring of another, otherwise the suffix stripped from virtual-hosted requests is
+/// ambiguous and requests get routed to bogus buckets.
+pub fn validate_proxy_hosts(domains: &[String]) -> ProxyResult<()> {
+    let invalid = |domain: &str, reason: String| {
+        Err(ProxyError::AssertFail(format!(
+            "invalid proxy host {domain:?}: {reason}"
+        )))
+    };
+    if domains.is_empty() {
+        return Err(ProxyError::AssertFail(
+            "proxy_hosts should not be empty".into(),
+        ));
+    }
+    for domain in domains {
+        let host = match domain.rsplit_once(':') {
+            Some((host, port)) => {
+                if port.parse::<u16>().is_err() {
+                    return invalid(domain, format!("port {port:?} not valid"));
+                }
+                host
+            }
+            None => domain.as_str(),
+        };
+        if let Err(reason) = validate_host_name(host) {
+            return invalid(domain, reason);
+        }
+    }
+    // host names are compared without the ports, a same host name may be served on several
+    // ports while a host name containing another one is ambiguous whatever the ports
+    for (i, domain) in domains.iter().enumerate() {
+        for (j, other) in domains.iter().enumerate() {
+            let (host, other_host) = (host_name(domain), host_name(other));
+            let overlaps = domain == other || (host != other_host && other_host.contains(host));
+            if i != j && overlaps {
+                return invalid(domain, format!("overlaps with proxy host {other:?}"));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn host_name(domain: &str) -> &str {
+    domain.rsplit_once(':').map_or(domain, |(host, _)| host)
+}
+
+fn validate_host_name(host: &str) -> Result<(), String> {
+    if host.is_empty() || host.len() > 253 {
+        return Err("host name should have 1 to 253 characters".into());
+    }
+    for label in host.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("label {label:?} should have 1 to 63 characters"));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(format!("label {label:?} should not start or end with '-'"));
+        }
+        if !label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        {
+            return Err(format!(
+                "label {label:?} should only contain letters, digits and '-'"
+            ));
+        }
+    }
+    Ok(())
+}
+
 pub fn features() -> String {
     let features = vec![
         #[cfg(feature = "uni-key")]
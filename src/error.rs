@@ -1,12 +1,96 @@
-#![allow(unused)]
-
+use http::{header::InvalidHeaderValue, uri::InvalidUri};
 use piam_object_storage::error::ParserError;
 use piam_proxy::error::ProxyError;
+use thiserror::Error;
 
 pub type S3ProxyResult<T> = Result<T, S3ProxyError>;
 
-#[derive(Debug)]
-pub enum S3ProxyError {}
+/// Failures of the s3 proxy, errors of piam are wrapped into [`S3ProxyError::Proxy`]
+#[derive(Debug, Error)]
+pub enum S3ProxyError {
+    #[error("path-style request not valid: {0}")]
+    PathStyle(String),
+    #[error("host not valid: {0}")]
+    InvalidHost(String),
+    #[error("host {host} not valid")]
+    InvalidHostHeader {
+        host: String,
+        #[source]
+        source: InvalidHeaderValue,
+    },
+    #[error("uri {uri} not valid")]
+    InvalidUri {
+        uri: String,
+        #[source]
+        source: InvalidUri,
+    },
+    #[error("access info not found for bucket: {0}")]
+    BucketNotFound(String),
+    #[error(
+        "there are more than one buckets with the same name in multiple regions, \
+         access info not found for bucket: {bucket} in region: {region}"
+    )]
+    AmbiguousBucket { bucket: String, region: String },
+    #[error("failed to sign request: {0}")]
+    Signing(String),
+    #[error("failed to forward request")]
+    Upstream(#[source] hyper::Error),
+    #[error("{0:?}")]
+    Proxy(ProxyError),
+}
+
+impl S3ProxyError {
+    /// Stable identifier of the kind of the error, e.g. for logs and metrics
+    pub fn code(&self) -> &'static str {
+        match self {
+            S3ProxyError::PathStyle(_) => "path_style",
+            S3ProxyError::InvalidHost(_) | S3ProxyError::InvalidHostHeader { .. } => "invalid_host",
+            S3ProxyError::InvalidUri { .. } => "invalid_uri",
+            S3ProxyError::BucketNotFound(_) => "bucket_not_found",
+            S3ProxyError::AmbiguousBucket { .. } => "ambiguous_bucket",
+            S3ProxyError::Signing(_) => "signing",
+            S3ProxyError::Upstream(_) => "upstream",
+            S3ProxyError::Proxy(_) => "proxy",
+        }
+    }
+
+    /// The error followed by its sources, separated by ": "
+    pub fn chain(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(e) = source {
+            message.push_str(&format!(": {e}"));
+            source = e.source();
+        }
+        message
+    }
+}
+
+impl From<ProxyError> for S3ProxyError {
+    fn from(e: ProxyError) -> Self {
+        S3ProxyError::Proxy(e)
+    }
+}
+
+impl From<S3ProxyError> for ProxyError {
+    fn from(e: S3ProxyError) -> Self {
+        match e {
+            S3ProxyError::Proxy(e) => e,
+            S3ProxyError::PathStyle(_) | S3ProxyError::InvalidUri { .. } => {
+                ProxyError::MalformedProtocol(e.chain())
+            }
+            S3ProxyError::InvalidHost(_) | S3ProxyError::InvalidHostHeader { .. } => {
+                ProxyError::InvalidEndpoint(e.chain())
+            }
+            S3ProxyError::BucketNotFound(_) | S3ProxyError::AmbiguousBucket { .. } => {
+                ProxyError::ResourceNotFound(e.chain())
+            }
+            S3ProxyError::Signing(_) | S3ProxyError::Upstream(_) => {
+                ProxyError::OtherInternal(e.chain())
+            }
+        }
+    }
+}
 
 pub fn from_parser_into_proxy_error(e: ParserError) -> ProxyError {
     ProxyError::ParserError(e.to_string())
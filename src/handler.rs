@@ -1,16 +1,16 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
 
 use axum::{
     extract::{ConnectInfo, Path, Query, State},
     response::IntoResponse,
 };
-use busylib::{
-    logger::change_debug,
-    prelude::{EnhancedExpect, EnhancedUnwrap},
-};
-use http::{Response, StatusCode};
+use busylib::{logger::change_debug, prelude::EnhancedUnwrap};
+use http::{header::CONTENT_TYPE, HeaderMap, Method, Request, Response, StatusCode};
 use hyper::Body;
-use log::debug;
+use log::{debug, log, Level};
 use piam_core::{
     account::aws::AwsAccount,
     condition::input::{Condition, ConditionCtx},
@@ -18,9 +18,9 @@ use piam_core::{
 use piam_object_storage::{input::ObjectStorageInput, policy::ObjectStoragePolicy};
 use piam_proxy::{
     container::{FoundPolicies, IamContainer, PolicyFilterParams},
-    error::ProxyResult,
+    error::{ProxyError, ProxyResult},
     policy::FindEffect,
-    request::{forward, AccessTarget, HttpRequestExt},
+    request::{AccessTarget, HttpRequestExt},
     response::HttpResponseExt,
     signature::{
         aws::{AwsSigv4, AwsSigv4SignParams},
@@ -29,30 +29,84 @@ use piam_proxy::{
     state::ArcState,
     type_alias::{HttpRequest, HttpResponse},
 };
+use serde::Deserialize;
+use tracing::{info_span, Instrument};
 
 use crate::{
-    config::SERVICE, error::from_parser_into_proxy_error, request::S3RequestTransform, S3Config,
+    access_log::{redact_headers, AccessRecord},
+    audit::AuditEvent,
+    auth::{self, SigV4Auth},
+    chunked::{self, ChunkSigner},
+    config::SERVICE,
+    error::{from_parser_into_proxy_error, S3ProxyError, S3ProxyResult},
+    manage, probe, refresh,
+    request::S3RequestTransform,
+    response::{into_s3_response, S3Error},
+    shadow, shutdown,
+    sigv4::{percent_decode, uri_encode, Scope},
+    telemetry,
+    upstream::forward,
+    S3Config,
 };
 
 pub type S3ProxyState = ArcState<ObjectStoragePolicy, S3Config>;
 
+/// Serving with a stale state is still serving, so a degraded proxy keeps answering 200 with a
+/// `DEGRADED` body meant for alerting, a draining one fails. Load balancers and orchestrators
+/// should gate traffic on `/_piam_readyz`, this endpoint only reports the refresh status.
 pub async fn health() -> impl IntoResponse {
-    "OK"
+    if shutdown::is_draining() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "DRAINING".to_string());
+    }
+    let status = refresh::status();
+    if !status.is_stale() {
+        return (StatusCode::OK, "OK".to_string());
+    }
+    let degraded = format!(
+        "DEGRADED: state last loaded {}s ago, {} consecutive failed refreshes",
+        status.age().unwrap_or_default(),
+        status.consecutive_failures
+    );
+    (StatusCode::OK, degraded)
+}
+
+#[cfg(feature = "uni-key")]
+pub async fn uni_key_status(
+    State(state): State<S3ProxyState>,
+    headers: HeaderMap,
+) -> ProxyResult<HttpResponse> {
+    if let Err(res) = manage::authorize(&state, &headers) {
+        return Ok(res);
+    }
+    let statuses = state.load().extended_config.get_uni_key_info()?.statuses();
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&statuses).unwp()))
+        .unwp())
 }
 
 pub async fn manage(
     State(state): State<S3ProxyState>,
     Query(params): Query<HashMap<String, String>>,
-    // mut req: Request<Body>,
+    headers: HeaderMap,
 ) -> HttpResponse {
-    // TODO: turn debug mode on/off
+    if let Err(res) = manage::authorize(&state, &headers) {
+        return res;
+    }
     fn resp(payload: &str) -> HttpResponse {
         Response::builder()
             .body(Body::from(payload.to_string()))
             .unwp()
     }
     if let Some(debug) = params.get("debug") {
-        let on = change_debug(state.load().log_handle.as_ref().unwp(), debug.as_str());
+        let state = state.load();
+        let Some(log_handle) = state.log_handle.as_ref() else {
+            return Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from("log level can not be changed"))
+                .unwp();
+        };
+        let on = change_debug(log_handle, debug.as_str());
         return if on {
             resp("debug mode on")
         } else {
@@ -70,64 +124,472 @@ pub async fn handle_path(
     State(state): State<S3ProxyState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     mut req: HttpRequest,
-) -> ProxyResult<HttpResponse> {
-    let proxy_hosts = &state.load().extended_config.proxy_hosts.domains;
-    req.adapt_path_style(path, proxy_hosts)?;
-    handle(State(state), ConnectInfo(addr), req).await
+) -> HttpResponse {
+    let span = telemetry::request_span(&req);
+    async move {
+        let mut record = AccessRecord::start(&req, addr);
+        let resource = req.uri().path().to_string();
+        let auth = match authenticate(&state, &req) {
+            Ok(auth) => auth,
+            Err(res) => return record.finish(res),
+        };
+        let proxy_hosts = &state.load().extended_config.proxy_hosts.domains;
+        if let Err(e) = req.adapt_path_style(path, proxy_hosts) {
+            return record.finish(into_s3_response(Err(e), &resource));
+        }
+        let result = proxy(state, addr, auth, req, &mut record).await;
+        record.finish(into_s3_response(result, &resource))
+    }
+    .instrument(span)
+    .await
 }
 
 pub async fn handle(
     State(state): State<S3ProxyState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     req: HttpRequest,
-) -> ProxyResult<HttpResponse> {
-    log(&req);
+) -> HttpResponse {
+    let span = telemetry::request_span(&req);
+    async move {
+        let mut record = AccessRecord::start(&req, addr);
+        let resource = req.uri().path().to_string();
+        let auth = match authenticate(&state, &req) {
+            Ok(auth) => auth,
+            Err(res) => return record.finish(res),
+        };
+        let result = proxy(state, addr, auth, req, &mut record).await;
+        record.finish(into_s3_response(result, &resource))
+    }
+    .instrument(span)
+    .await
+}
+
+/// Default lifetime in seconds of issued presigned urls
+pub const DEFAULT_PRESIGN_EXPIRES: i64 = 3600;
+
+#[derive(Debug, Deserialize)]
+pub struct PresignParams {
+    bucket: String,
+    key: String,
+    /// GET, PUT, HEAD or DELETE, defaults to GET
+    method: Option<String>,
+    /// seconds, defaults to [`DEFAULT_PRESIGN_EXPIRES`]
+    expires: Option<i64>,
+}
+
+/// Issue a presigned url pointing at the proxy, signed with the credentials of the caller.
+/// The policies are checked now, and again through the usual pipeline when the url is used.
+pub async fn presign(
+    State(state): State<S3ProxyState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<PresignParams>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let _in_flight = shutdown::track_request(&req, addr);
+    let auth = match authenticate(&state, &req) {
+        Ok(auth) => auth,
+        Err(res) => return res,
+    };
+    let result = issue_presigned_url(&state, addr, &auth, params, &req).await;
+    into_s3_response(result, req.uri().path())
+}
+
+async fn issue_presigned_url(
+    state: &S3ProxyState,
+    addr: SocketAddr,
+    auth: &SigV4Auth,
+    params: PresignParams,
+    req: &HttpRequest,
+) -> S3ProxyResult<HttpResponse> {
+    use http::header::HOST;
+
+    let resource = req.uri().path();
+    let invalid = |message: &str| {
+        let e = S3Error::new(StatusCode::BAD_REQUEST, "InvalidArgument", message);
+        Ok(e.into_response(resource))
+    };
+    if auth.is_presigned() {
+        return Ok(
+            S3Error::access_denied("presigned urls can not be issued by a presigned url")
+                .into_response(resource),
+        );
+    }
+    let method = match params
+        .method
+        .as_deref()
+        .unwrap_or("GET")
+        .to_uppercase()
+        .as_str()
+    {
+        "GET" => Method::GET,
+        "PUT" => Method::PUT,
+        "HEAD" => Method::HEAD,
+        "DELETE" => Method::DELETE,
+        _ => return invalid("method should be one of GET, PUT, HEAD and DELETE"),
+    };
+    let expires = params.expires.unwrap_or(DEFAULT_PRESIGN_EXPIRES);
+    if !(1..=auth::MAX_PRESIGNED_EXPIRES).contains(&expires) {
+        return invalid("expires out of range");
+    }
+    if params.bucket.is_empty() || params.bucket.contains('/') || params.key.is_empty() {
+        return invalid("bucket and key should be valid");
+    }
+
+    let state = state.load();
+    let s3_config = &state.extended_config;
+    let iam_container = &state.iam_container;
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_default();
+    let proxy_host = s3_config
+        .proxy_hosts
+        .find_proxy_host(host)
+        .map_err(from_parser_into_proxy_error)?;
+
+    // check the policies at issue time with the request the url stands for
+    let path = format!("/{}", params.key);
+    let target = Request::builder()
+        .method(method.clone())
+        .uri(uri_encode(&path, true))
+        .header(HOST, format!("{}.{}", params.bucket, proxy_host))
+        .body(Body::empty())
+        .unwp();
+    let (input, target) = ObjectStorageInput::parse(target, &s3_config.proxy_hosts)
+        .await
+        .map_err(from_parser_into_proxy_error)?
+        .into_parts();
+    let (access_target, base_access_key) =
+        get_access_params(iam_container, s3_config, &input, auth).await?;
+    let mut audit = AuditEvent::new(
+        addr,
+        &base_access_key,
+        format!("Presign{:?}", input.action_kind()),
+        input.bucket(),
+        Some(params.key.clone()),
+    );
+    let policies =
+        find_matching_policies(&access_target, &base_access_key, iam_container, &mut audit)?;
+    apply_policies_to_req(addr, &input, policies, target, &mut audit)?;
+
+    let now = auth::unix_now();
+    let secret_key = auth::user_secret_key(iam_container, &auth.access_key)?;
+    let path_and_query = match auth::presign(
+        &method,
+        proxy_host,
+        &format!("/{}{}", params.bucket, path),
+        &auth.access_key,
+        secret_key,
+        &auth.scope.region,
+        expires,
+        now,
+    ) {
+        Ok(path_and_query) => path_and_query,
+        Err(e) => return Ok(e.into_response(resource)),
+    };
+    // the proxy is reached by https once it terminates tls itself, unless a trusted reverse
+    // proxy in front of it tells otherwise
+    let default_scheme = if s3_config.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let forwarded_scheme = req
+        .headers()
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .filter(|scheme| matches!(*scheme, "http" | "https"))
+        .filter(|_| s3_config.trusted_proxies.contains(&addr.ip()));
+    let scheme = forwarded_scheme.unwrap_or(default_scheme);
+    let payload = serde_json::json!({
+        "url": format!("{scheme}://{proxy_host}{path_and_query}"),
+        "method": method.as_str(),
+        "expires_at": now + expires,
+    });
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(payload.to_string()))
+        .unwp())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExplainParams {
+    /// access key as used by the client
+    access_key: String,
+    bucket: String,
+    key: Option<String>,
+    /// S3 operation, e.g. GetObject, PutObject or ListObjectsV2
+    action: String,
+    source_ip: IpAddr,
+    /// region of the request, defaults to [`DEFAULT_EXPLAIN_REGION`]
+    region: Option<String>,
+}
+
+pub const DEFAULT_EXPLAIN_REGION: &str = "us-east-1";
+
+/// Simulate a request and explain the decision of the policies, without forwarding anything
+pub async fn explain(
+    State(state): State<S3ProxyState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<ExplainParams>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let _in_flight = shutdown::track_request(&req, addr);
+    if let Err(res) = manage::authorize(&state, req.headers()) {
+        return res;
+    }
+    into_s3_response(explain_access(&state, params).await, req.uri().path())
+}
+
+async fn explain_access(
+    state: &S3ProxyState,
+    params: ExplainParams,
+) -> S3ProxyResult<HttpResponse> {
+    use http::header::HOST;
+
+    let state = state.load();
+    let s3_config = &state.extended_config;
+    let iam_container = &state.iam_container;
+    let proxy_host = s3_config.proxy_hosts.domains.first().ok_or_else(|| {
+        ProxyError::AssertFail("at least one proxy host should be configured".into())
+    })?;
+    let Some((method, path)) = action_to_request(&params.action, params.key.as_deref()) else {
+        let e = S3Error::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidArgument",
+            format!("action {} not supported", params.action),
+        );
+        return Ok(e.into_response("/_piam_explain"));
+    };
+    let probe = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(HOST, format!("{}.{}", params.bucket, proxy_host))
+        .body(Body::empty())
+        .map_err(|e| ProxyError::MalformedProtocol(format!("request not valid: {e}")))?;
+    let (input, probe) = ObjectStorageInput::parse(probe, &s3_config.proxy_hosts)
+        .await
+        .map_err(from_parser_into_proxy_error)?
+        .into_parts();
+
+    let auth = SigV4Auth {
+        access_key: params.access_key.clone(),
+        scope: Scope {
+            date: String::new(),
+            region: params
+                .region
+                .unwrap_or_else(|| DEFAULT_EXPLAIN_REGION.to_string()),
+            service: SERVICE.to_string(),
+        },
+        signed_headers: vec![],
+        signature: String::new(),
+        timestamp: String::new(),
+        expires: None,
+    };
+    let (access_target, base_access_key) =
+        get_access_params(iam_container, s3_config, &input, &auth).await?;
+    let user = iam_container.find_user_by_base_access_key(&base_access_key)?;
+    let groups = iam_container.find_groups_by_user(user)?;
+
+    // a disabled audit event collects the evaluation without writing it to the trail
+    let mut audit = AuditEvent::default();
+    let addr = SocketAddr::new(params.source_ip, 0);
+    let _ = find_matching_policies(&access_target, &base_access_key, iam_container, &mut audit)
+        .and_then(|policies| apply_policies_to_req(addr, &input, policies, probe, &mut audit));
+
+    let payload = serde_json::json!({
+        "user": { "id": user.id, "name": user.name },
+        "groups": groups
+            .iter()
+            .map(|group| serde_json::json!({ "id": group.id, "name": group.name }))
+            .collect::<Vec<_>>(),
+        "account": access_target.account.code,
+        "region": access_target.region,
+        "action": format!("{:?}", input.action_kind()),
+        "matched_policies": audit.matched_policies,
+        "condition_effects": audit.condition_effects,
+        "user_input_effects": audit.user_input_effects,
+        "decision": audit.decision,
+        "reason": audit.reason,
+    });
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(payload.to_string()))
+        .unwp())
+}
+
+/// Method and path of the request standing for an S3 operation
+fn action_to_request(action: &str, key: Option<&str>) -> Option<(Method, String)> {
+    let object = |method| key.map(|key| (method, uri_encode(&format!("/{key}"), true)));
+    match action {
+        "GetObject" => object(Method::GET),
+        "PutObject" => object(Method::PUT),
+        "HeadObject" => object(Method::HEAD),
+        "DeleteObject" => object(Method::DELETE),
+        "GetObjectTagging" => object(Method::GET).map(|(m, p)| (m, format!("{p}?tagging"))),
+        "ListObjects" => Some((Method::GET, "/".to_string())),
+        "ListObjectsV2" => Some((Method::GET, "/?list-type=2".to_string())),
+        "HeadBucket" => Some((Method::HEAD, "/".to_string())),
+        "DeleteObjects" => Some((Method::POST, "/?delete".to_string())),
+        _ => None,
+    }
+}
+
+/// Verify the signature of the client, before the request gets rewritten
+fn authenticate(state: &S3ProxyState, req: &HttpRequest) -> Result<SigV4Auth, HttpResponse> {
+    let _span = info_span!("authenticate").entered();
+    auth::verify(&state.load().iam_container, req).map_err(|e| {
+        debug!("authentication failed: {:?}", e);
+        e.into_response(req.uri().path())
+    })
+}
+
+async fn proxy(
+    state: S3ProxyState,
+    addr: SocketAddr,
+    auth: SigV4Auth,
+    mut req: HttpRequest,
+    record: &mut AccessRecord,
+) -> S3ProxyResult<HttpResponse> {
+    record.access_key = Some(auth.access_key.clone());
+    if auth.is_presigned() {
+        // the upstream request is header-signed for the target account
+        req.strip_presigned_query()?;
+    }
     req.validate()?;
+    chunked::check_supported(&req)?;
 
     let state = state.load();
     let s3_config = &state.extended_config;
     let iam_container = &state.iam_container;
+    log(&req, auth::user_id(iam_container, &auth.access_key).ok());
 
     let (input, req) = ObjectStorageInput::parse(req, &s3_config.proxy_hosts)
+        .instrument(info_span!("parse"))
         .await
         .map_err(from_parser_into_proxy_error)?
         .into_parts();
+    record.action = Some(format!("{:?}", input.action_kind()));
+    if !input.bucket().is_empty() {
+        record.bucket = Some(input.bucket().to_string());
+    }
+    if req.uri().path() != "/" {
+        record.key = Some(percent_decode(req.uri().path().trim_start_matches('/')));
+    }
+
+    #[cfg(feature = "uni-key")]
+    if input.action_kind() == piam_object_storage::input::ActionKind::ListBuckets {
+        let res = list_buckets(addr, iam_container, s3_config, &auth, &req, record).await?;
+        return Ok(res.add_piam_headers_with_random_id());
+    }
 
     let (access_target, base_access_key) =
-        get_access_params(iam_container, s3_config, &input, &req)?;
-    let policies = find_matching_policies(&access_target, &base_access_key, iam_container)?;
-    let req = apply_policies_to_req(addr, &input, policies, req)?;
-    let signed_req = sign(s3_config, access_target, req).await?;
-    let res = forward(signed_req, &state.http_client).await?;
+        get_access_params(iam_container, s3_config, &input, &auth)
+            .instrument(info_span!("get_access_params"))
+            .await?;
+    record.access_key = Some(base_access_key.clone());
+    record.account = Some(access_target.account.code.clone());
+    record.region = Some(access_target.region.clone());
+    let mut audit = AuditEvent::new(
+        addr,
+        &base_access_key,
+        format!("{:?}", input.action_kind()),
+        input.bucket(),
+        record.key.clone(),
+    );
+    // the candidate is evaluated even when no live policy is found, which it may well change
+    let shadow_allowed = s3_config.shadow_policies.as_ref().map(|candidate| {
+        let _span = info_span!("shadow_evaluation").entered();
+        find_policies(&access_target, &base_access_key, &candidate.iam_container)
+            .and_then(|policies| {
+                apply_effects(
+                    addr,
+                    &input,
+                    &policies,
+                    probe_of(&req),
+                    &mut AuditEvent::default(),
+                )
+            })
+            .is_ok()
+    });
+    let applied = info_span!("find_matching_policies")
+        .in_scope(|| {
+            find_matching_policies(&access_target, &base_access_key, iam_container, &mut audit)
+        })
+        .and_then(|policies| {
+            info_span!("apply_policies_to_req")
+                .in_scope(|| apply_policies_to_req(addr, &input, policies, req, &mut audit))
+        });
+    if let Some(shadow_allowed) = shadow_allowed {
+        shadow::compare(applied.is_ok(), shadow_allowed, record);
+    }
+    let req = applied.map_err(|e| {
+        record.decision = Some("deny");
+        e
+    })?;
+    record.decision = Some("allow");
+    let chunk_verifier = if chunked::is_streaming(&req) {
+        let user = iam_container.find_user_by_base_access_key(&base_access_key)?;
+        Some(ChunkSigner::from_seed(&req, &user.secret_key)?)
+    } else {
+        None
+    };
+    let account_secret_key = access_target.account.secret_key.clone();
+    let mut signed_req = sign(s3_config, access_target, req)
+        .instrument(info_span!("sign"))
+        .await?;
+    if let Some(verifier) = chunk_verifier {
+        let signer = ChunkSigner::from_seed(&signed_req, &account_secret_key)?;
+        signed_req = chunked::resign_body(signed_req, verifier, signer);
+    }
+    let res = forward(signed_req, s3_config.get_upstream_client()?)
+        .instrument(info_span!("forward"))
+        .await?;
     Ok(res.add_piam_headers_with_random_id())
 }
 
-fn log(req: &HttpRequest) {
-    debug!("req.uri '{}'", req.uri());
-    debug!("req.method {}", req.method());
-    debug!("req.headers {:#?}", req.headers());
+/// Body-less copy of a request, for evaluating policies without consuming it
+fn probe_of(req: &HttpRequest) -> HttpRequest {
+    let mut probe = Request::new(Body::empty());
+    *probe.method_mut() = req.method().clone();
+    *probe.uri_mut() = req.uri().clone();
+    *probe.headers_mut() = req.headers().clone();
+    probe
 }
 
-fn get_access_params(
+fn log(req: &HttpRequest, user_id: Option<&str>) {
+    let level = if user_id.map_or(false, manage::is_debug_user) {
+        Level::Info
+    } else {
+        Level::Debug
+    };
+    log!(level, "req.uri '{}'", req.uri().path());
+    log!(level, "req.method {}", req.method());
+    log!(level, "req.headers {:#?}", redact_headers(req.headers()));
+}
+
+async fn get_access_params(
     iam_container: &IamContainer<ObjectStoragePolicy>,
     s3_config: &S3Config,
     input: &ObjectStorageInput,
-    req: &HttpRequest,
-) -> ProxyResult<(AccessTarget, String)> {
+    auth: &SigV4Auth,
+) -> S3ProxyResult<(AccessTarget, String)> {
     // aws sigv4 specific
     #[allow(unused)]
-    let (access_key, region) = req.extract_access_key_and_region()?;
+    let (access_key, region) = (auth.access_key.as_str(), auth.scope.region.as_str());
     // When feature uni-key is enabled, base_access_key is aws access_key,
     // otherwise base_access_key + account_code = aws_access_key
     #[cfg(feature = "uni-key")]
     let (access_target, base_access_key) = {
         let access_info = s3_config
             .get_uni_key_info()?
-            .find_access_info(&input, region)?;
+            .find_access_info(input, region)
+            .await?;
         (
             AccessTarget {
-                account: access_info.account.clone(),
-                region: access_info.region.clone(),
+                account: access_info.account,
+                region: access_info.region,
             },
             access_key,
         )
@@ -152,6 +614,29 @@ fn find_matching_policies<'a>(
     access_target: &AccessTarget,
     base_access_key: &str,
     iam_container: &'a IamContainer<ObjectStoragePolicy>,
+    audit: &mut AuditEvent,
+) -> ProxyResult<FoundPolicies<'a, ObjectStoragePolicy>> {
+    audit.account = access_target.account.code.clone();
+    audit.region = access_target.region.clone();
+    let found = find_policies(access_target, base_access_key, iam_container);
+    match &found {
+        Ok(policies) => {
+            audit.matched_policies = policies
+                .condition
+                .iter()
+                .map(|policy| policy.id.clone())
+                .chain(policies.user_input.iter().map(|policy| policy.id.clone()))
+                .collect()
+        }
+        Err(e) => audit.deny(format!("{:?}", e)),
+    }
+    found
+}
+
+fn find_policies<'a>(
+    access_target: &AccessTarget,
+    base_access_key: &str,
+    iam_container: &'a IamContainer<ObjectStoragePolicy>,
 ) -> ProxyResult<FoundPolicies<'a, ObjectStoragePolicy>> {
     let user = iam_container.find_user_by_base_access_key(base_access_key)?;
     let groups = iam_container.find_groups_by_user(user)?;
@@ -166,27 +651,134 @@ fn apply_policies_to_req(
     input: &ObjectStorageInput,
     policies: FoundPolicies<ObjectStoragePolicy>,
     req: HttpRequest,
+    audit: &mut AuditEvent,
+) -> ProxyResult<HttpRequest> {
+    let applied = apply_effects(addr, input, &policies, req, audit);
+    match &applied {
+        Ok(_) => audit.allow(),
+        Err(e) => audit.deny(format!("{:?}", e)),
+    }
+    applied
+}
+
+fn apply_effects(
+    addr: SocketAddr,
+    input: &ObjectStorageInput,
+    policies: &FoundPolicies<ObjectStoragePolicy>,
+    req: HttpRequest,
+    audit: &mut AuditEvent,
 ) -> ProxyResult<HttpRequest> {
     let condition_ctx = ConditionCtx::default().from(Condition::new_with_addr(addr));
     let condition_effects = policies.condition.find_effects(&condition_ctx)?;
+    audit.condition_effects = Some(format!("{:?}", condition_effects));
     let req = req.apply_effects(condition_effects)?;
 
     let user_input_effects = policies.user_input.find_effects(&input)?;
-    let mut req = req.apply_effects(user_input_effects)?;
+    audit.user_input_effects = Some(format!("{:?}", user_input_effects));
+    let req = req.apply_effects(user_input_effects)?;
     Ok(req)
 }
 
+/// Answer ListBuckets from the merged bucket map of all accounts,
+/// a bucket is listed only if the policies of the user allow accessing it.
+/// The policies are found once per account and region, then applied to a HeadBucket of every
+/// bucket of them.
+#[cfg(feature = "uni-key")]
+async fn list_buckets(
+    addr: SocketAddr,
+    iam_container: &IamContainer<ObjectStoragePolicy>,
+    s3_config: &S3Config,
+    auth: &SigV4Auth,
+    req: &HttpRequest,
+    record: &mut AccessRecord,
+) -> S3ProxyResult<HttpResponse> {
+    use http::header::HOST;
+
+    let access_key = auth.access_key.as_str();
+    let mut audit = AuditEvent::new(addr, access_key, "ListBuckets".into(), "", None);
+    // reject unknown users rather than listing nothing
+    let user = match iam_container.find_user_by_base_access_key(access_key) {
+        Ok(user) => user,
+        Err(e) => {
+            audit.deny(format!("{:?}", e));
+            record.decision = Some("deny");
+            return Err(e.into());
+        }
+    };
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_default();
+    let proxy_host = s3_config
+        .proxy_hosts
+        .find_proxy_host(host)
+        .map_err(from_parser_into_proxy_error)?;
+
+    let mut groups: HashMap<(String, String), (AccessTarget, Vec<String>)> = HashMap::new();
+    for (bucket, access_info) in s3_config.get_uni_key_info()?.buckets() {
+        let key = (access_info.account.code.clone(), access_info.region.clone());
+        groups
+            .entry(key)
+            .or_insert_with(|| {
+                let access_target = AccessTarget {
+                    account: access_info.account,
+                    region: access_info.region,
+                };
+                (access_target, vec![])
+            })
+            .1
+            .push(bucket);
+    }
+    let mut visible = std::collections::BTreeSet::new();
+    for (access_target, buckets) in groups.into_values() {
+        let Ok(policies) = find_policies(&access_target, access_key, iam_container) else {
+            continue;
+        };
+        for bucket in buckets {
+            if visible.contains(&bucket) {
+                continue;
+            }
+            // evaluate the policies against a HeadBucket of the bucket
+            let probe = Request::builder()
+                .method(Method::HEAD)
+                .uri("/")
+                .header(HOST, format!("{bucket}.{proxy_host}"))
+                .body(Body::empty())
+                .unwp();
+            let Ok(parsed) = ObjectStorageInput::parse(probe, &s3_config.proxy_hosts).await else {
+                continue;
+            };
+            let (input, probe) = parsed.into_parts();
+            // a disabled event keeps the probes out of the trail, the listing is recorded below
+            let mut probe_audit = AuditEvent::default();
+            if apply_effects(addr, &input, &policies, probe, &mut probe_audit).is_ok() {
+                visible.insert(bucket);
+            }
+        }
+    }
+    audit.reason = Some(format!("{} buckets visible", visible.len()));
+    audit.allow();
+    record.decision = Some("allow");
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/xml")
+        .body(Body::from(crate::uni_key::list_all_my_buckets_result(
+            &user.id, &user.name, &visible,
+        )))
+        .unwp())
+}
+
 async fn sign(
     s3_config: &S3Config,
     access_target: AccessTarget,
     mut req: HttpRequest,
-) -> ProxyResult<HttpRequest> {
+) -> S3ProxyResult<HttpRequest> {
     req.set_actual_host(s3_config, &access_target.region)?;
+    probe::record_forwarded_region(&access_target.region);
     let sign_params =
         AwsSigv4SignParams::new_with(&access_target.account, SERVICE, &access_target.region);
-    let signed_req = req
-        .sign_with_aws_sigv4_params(&sign_params)
+    req.sign_with_aws_sigv4_params(&sign_params)
         .await
-        .ex("sign should not fail");
-    Ok(signed_req)
+        .map_err(|e| S3ProxyError::Signing(format!("{:?}", e)))
 }
@@ -0,0 +1,61 @@
+use axum::{
+    routing::{any, get, post, put},
+    Router,
+};
+
+pub use crate::config::S3Config;
+use crate::handler::S3ProxyState;
+
+pub mod access_log;
+pub mod audit;
+pub mod auth;
+pub mod chunked;
+pub mod config;
+pub mod error;
+pub mod handler;
+pub mod local;
+pub mod manage;
+pub mod metrics;
+pub mod probe;
+pub mod refresh;
+pub mod request;
+pub mod response;
+pub mod shadow;
+pub mod shutdown;
+pub mod sigv4;
+pub mod telemetry;
+pub mod tls;
+#[cfg(feature = "uni-key")]
+pub mod uni_key;
+pub mod upstream;
+
+/// Build the proxy router, shared by the binary and the integration tests
+pub fn router(state: S3ProxyState) -> Router {
+    let routes = Router::new()
+        .route("/health", get(handler::health))
+        // internal endpoints are reserved under `/_piam_`, any other path may be an object key
+        // or a path-style bucket
+        .route("/_piam_livez", get(probe::livez))
+        .route("/_piam_readyz", get(probe::readyz))
+        .route("/_piam_metrics", get(metrics::metrics))
+        .route("/_piam_manage_api", put(handler::manage))
+        .route("/_piam_manage_api/reload", post(manage::reload))
+        .route("/_piam_manage_api/config", get(manage::config))
+        .route("/_piam_manage_api/state", get(manage::state_info))
+        .route("/_piam_manage_api/debug_user", put(manage::debug_user))
+        .route("/_piam_presign", get(handler::presign))
+        .route("/_piam_explain", get(handler::explain));
+    #[cfg(feature = "uni-key")]
+    let routes = routes
+        .route("/_piam_uni_key_status", get(handler::uni_key_status))
+        .route(
+            "/_piam_manage_api/uni_key_buckets",
+            get(manage::uni_key_buckets),
+        );
+    routes
+        // the router for ListBucket only
+        .route("/", any(handler::handle))
+        // the router for other operations
+        .route("/*path", any(handler::handle_path))
+        .with_state(state)
+}
@@ -0,0 +1,149 @@
+//! Local source of the state, read from a directory instead of the config service, e.g. for CI,
+//! air-gapped deployments or outages of the config service. The directory holds:
+//! - `core_config.yaml`: accounts, users, groups and policies
+//! - `s3_config.yaml`: the [`S3Config`], with `uni_key_static_buckets` to run uni-key offline
+//!
+//! `.yml` and `.json` files are read as well. The files are watched, a changed config failing
+//! to load is refused and the last good state keeps serving.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{info, warn};
+use piam_object_storage::policy::ObjectStoragePolicy;
+use piam_proxy::{
+    config::CoreConfig,
+    container::IamContainer,
+    error::{ProxyError, ProxyResult},
+    state::{ExtendedState, ProxyState},
+};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    config::LOCAL_CONFIG_CHECK_INTERVAL, handler::S3ProxyState, metrics, refresh, shutdown,
+    S3Config,
+};
+
+/// Directory of the local config, the config service is used when unset
+pub const LOCAL_CONFIG_DIR_ENV: &str = "S3_PROXY_CONFIG_DIR";
+const CORE_CONFIG: &str = "core_config";
+const S3_CONFIG: &str = "s3_config";
+const EXTENSIONS: &[&str] = &["yaml", "yml", "json"];
+
+/// Build the state from the configs, as the config service does once they are fetched
+pub async fn build_state(
+    core_config: &CoreConfig<ObjectStoragePolicy>,
+    s3_config: S3Config,
+) -> ProxyResult<ProxyState<ObjectStoragePolicy, S3Config>> {
+    Ok(ProxyState {
+        iam_container: IamContainer::new_from(core_config)?,
+        extended_config: S3Config::new_from(s3_config)?
+            .with_core_config(core_config)
+            .await?,
+        http_client: Default::default(),
+        log_handle: None,
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct LocalSource {
+    pub dir: PathBuf,
+}
+
+impl LocalSource {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn from_env() -> Option<Self> {
+        std::env::var_os(LOCAL_CONFIG_DIR_ENV).map(Self::new)
+    }
+
+    pub async fn load(&self) -> ProxyResult<ProxyState<ObjectStoragePolicy, S3Config>> {
+        let core_config: CoreConfig<ObjectStoragePolicy> = read(&self.file(CORE_CONFIG)?)?;
+        let s3_config: S3Config = read(&self.file(S3_CONFIG)?)?;
+        build_state(&core_config, s3_config).await
+    }
+
+    /// Load the configs again into `state`, which is left as is if they fail to load.
+    /// The log handle of `state` is carried over.
+    pub async fn reload(&self, state: &S3ProxyState) -> bool {
+        let started = Instant::now();
+        let result = self.load().await;
+        metrics::observe_state_refresh(result.is_ok(), started.elapsed());
+        match result {
+            Ok(mut new_state) => {
+                let previous = state.load();
+                previous
+                    .extended_config
+                    .warn_unapplied_changes(&new_state.extended_config);
+                new_state.log_handle = previous.log_handle.clone();
+                state.store(Arc::new(new_state));
+                refresh::record_load();
+                info!("local config reloaded from {}", self.dir.display());
+                true
+            }
+            Err(e) => {
+                warn!("local config refused, keep serving the last good state: {e:?}");
+                false
+            }
+        }
+    }
+
+    /// Reload `state` whenever one of the files changes, or is requested by the management API,
+    /// until shutdown
+    pub fn watch(self, state: S3ProxyState) {
+        tokio::spawn(async move {
+            let interval = Duration::from_secs(LOCAL_CONFIG_CHECK_INTERVAL);
+            let mut last_modified = self.last_modified();
+            loop {
+                let requested = tokio::select! {
+                    _ = tokio::time::sleep(interval) => false,
+                    _ = refresh::reload_requested() => true,
+                    _ = shutdown::requested() => return,
+                };
+                let modified = self.last_modified();
+                if modified == last_modified && !requested {
+                    // the state is as fresh as the files, it does not get stale
+                    refresh::record_check();
+                    continue;
+                }
+                // files may be half written, retry on next check
+                if self.reload(&state).await {
+                    last_modified = modified;
+                }
+            }
+        });
+    }
+
+    fn file(&self, name: &str) -> ProxyResult<PathBuf> {
+        EXTENSIONS
+            .iter()
+            .map(|extension| self.dir.join(format!("{name}.{extension}")))
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                ProxyError::AssertFail(format!("{name}.yaml not found in {}", self.dir.display()))
+            })
+    }
+
+    fn last_modified(&self) -> Vec<Option<SystemTime>> {
+        [CORE_CONFIG, S3_CONFIG]
+            .iter()
+            .map(|name| {
+                let path = self.file(name).ok()?;
+                std::fs::metadata(path).and_then(|m| m.modified()).ok()
+            })
+            .collect()
+    }
+}
+
+/// Yaml being a superset of json, both are read by the yaml parser
+fn read<T: DeserializeOwned>(path: &Path) -> ProxyResult<T> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| ProxyError::AssertFail(format!("failed to read {}: {e}", path.display())))?;
+    serde_yaml::from_str(&content)
+        .map_err(|e| ProxyError::AssertFail(format!("{} not valid: {e}", path.display())))
+}
@@ -1,66 +1,125 @@
 // #![allow(unused)]
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use axum::{
-    routing::{any, get, put},
-    Router,
-};
+use arc_swap::ArcSwap;
+use axum_server::Handle;
 use busylib::{logger::init_logger, prelude::EnhancedUnwrap};
 use log::info;
 use patsnap_constants::policy_model::OBJECT_STORAGE;
 use piam_proxy::{
-    config::{server_port, set_constants, STATE_UPDATE_INTERVAL},
+    config::{server_port, set_constants},
     state::StateManager,
 };
-
-use crate::{
-    config::{features, S3Config, SERVICE},
+use s3_proxy::{
+    config::{features, SERVICE, SHUTDOWN_DEADLINE},
     handler::S3ProxyState,
+    local::LocalSource,
+    refresh, router, shutdown, telemetry,
 };
 
-mod config;
-mod error;
-mod handler;
-mod request;
-#[cfg(feature = "uni-key")]
-mod uni_key;
-
 #[tokio::main]
 async fn main() {
     let bin_name = env!("CARGO_PKG_NAME").replace('-', "_");
     let enable_logging = &["busylib", "piam-core", "piam_proxy", "piam-object-storage"];
-    let (_guard, _log_handle) = init_logger(&bin_name, enable_logging, true);
+    let (_guard, log_handle) = init_logger(&bin_name, enable_logging, true);
     set_constants("[Patsnap S3 Proxy]", OBJECT_STORAGE, SERVICE);
 
-    // TODO: make this async
-    let state_manager = StateManager::initialize().await;
-    let state: S3ProxyState = state_manager.arc_state.clone();
-    // TODO: move this into state::StateManager
-    tokio::spawn(async move {
-        loop {
-            tokio::time::sleep(std::time::Duration::from_secs(STATE_UPDATE_INTERVAL)).await;
-            state_manager.update_state().await;
+    let (state, refresher): (S3ProxyState, _) = match LocalSource::from_env() {
+        Some(source) => {
+            info!("state loaded from local config {}", source.dir.display());
+            let mut initial = source.load().await.unwp();
+            // `?debug=` of the management API changes the log level through it
+            initial.log_handle = Some(log_handle);
+            let state = Arc::new(ArcSwap::from_pointee(initial));
+            refresh::record_load();
+            source.watch(state.clone());
+            (state, None)
         }
-    });
+        None => {
+            // TODO: make this async
+            let state_manager = StateManager::initialize().await;
+            let state = state_manager.arc_state.clone();
+            (state, Some(refresh::spawn(state_manager)))
+        }
+    };
+    tokio::spawn(shutdown::listen());
 
-    let routes = Router::new()
-        .route("/health", get(handler::health))
-        .route("/_piam_manage_api", put(handler::manage))
-        // the router for ListBucket only
-        .route("/", any(handler::handle))
-        // the router for other operations
-        .route("/*path", any(handler::handle_path))
-        .with_state(state);
+    let (tls, proxy_hosts, shutdown_deadline) = {
+        let config = &state.load().extended_config;
+        if let Some(access_log) = &config.access_log {
+            access_log.init().unwp();
+        }
+        if let Some(audit_log) = &config.audit_log {
+            audit_log.init().unwp();
+        }
+        if let Some(tracing) = &config.tracing {
+            tracing.init().unwp();
+        }
+        (
+            config.tls.clone(),
+            config.proxy_hosts.domains.clone(),
+            config.shutdown_deadline.unwrap_or(SHUTDOWN_DEADLINE),
+        )
+    };
+    let app = router(state).into_make_service_with_connect_info::<SocketAddr>();
+    let deadline = Duration::from_secs(shutdown_deadline);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], server_port()));
-    info!(
-        "S3 compliant proxy listening on {} with features {}",
-        addr,
-        features()
-    );
-    axum::Server::bind(&addr)
-        .serve(routes.into_make_service_with_connect_info::<SocketAddr>())
-        .await
-        .unwp();
+    let http = |app| async move {
+        axum::Server::bind(&addr)
+            .serve(app)
+            .with_graceful_shutdown(shutdown::requested())
+            .await
+            .unwp()
+    };
+    match tls {
+        None => {
+            info!(
+                "S3 compliant proxy listening on {} with features {}",
+                addr,
+                features()
+            );
+            shutdown::drain(http(app), deadline).await;
+        }
+        Some(tls) => {
+            let rustls_config = tls.load(&proxy_hosts).await.unwp();
+            tls.clone().watch(rustls_config.clone());
+            let tls_addr = SocketAddr::from(([0, 0, 0, 0], tls.port));
+            info!(
+                "S3 compliant proxy listening on {} (https){} with features {}",
+                tls_addr,
+                if tls.http {
+                    format!(" and {} (http)", addr)
+                } else {
+                    String::new()
+                },
+                features()
+            );
+            let handle = Handle::new();
+            let https = axum_server::bind_rustls(tls_addr, rustls_config)
+                .handle(handle.clone())
+                .serve(app.clone());
+            tokio::spawn(async move {
+                shutdown::requested().await;
+                // the deadline is enforced by shutdown::drain for both listeners
+                handle.graceful_shutdown(None);
+            });
+            let servers = async {
+                if tls.http {
+                    let (https, ()) = tokio::join!(https, http(app));
+                    https.unwp();
+                } else {
+                    https.await.unwp();
+                }
+            };
+            shutdown::drain(servers, deadline).await;
+        }
+    }
+
+    if let Some(refresher) = refresher {
+        refresher.cancel().await;
+    }
+    telemetry::shutdown();
+    info!("S3 compliant proxy stopped");
 }
@@ -0,0 +1,168 @@
+//! Management API, every operation requires the admin token as a bearer token
+
+use std::{collections::HashSet, sync::RwLock};
+
+use axum::extract::{Query, State};
+use busylib::{config::dev_mode, prelude::EnhancedUnwrap};
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    HeaderMap, Response, StatusCode,
+};
+use hyper::Body;
+use log::info;
+use once_cell::sync::Lazy;
+use piam_proxy::type_alias::HttpResponse;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{handler::S3ProxyState, refresh, sigv4::signature_eq};
+
+/// Fields of the config never dumped
+const SECRET_FIELDS: &[&str] = &["admin_token", "secret_key", "access_key"];
+
+/// Ids of the users in debug
+static DEBUG_USERS: Lazy<RwLock<HashSet<String>>> = Lazy::new(Default::default);
+
+/// Check the admin token of a management request. Without a configured token the management
+/// API is only available in dev mode.
+pub fn authorize(state: &S3ProxyState, headers: &HeaderMap) -> Result<(), HttpResponse> {
+    let state = state.load();
+    let Some(token) = state.extended_config.admin_token.as_deref() else {
+        return if dev_mode() {
+            Ok(())
+        } else {
+            Err(plain(StatusCode::FORBIDDEN, "admin token not configured"))
+        };
+    };
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if signature_eq(provided, token) {
+        Ok(())
+    } else {
+        Err(plain(StatusCode::UNAUTHORIZED, "invalid admin token"))
+    }
+}
+
+/// Requests of the users in debug get logged in detail at info level
+pub fn is_debug_user(user_id: &str) -> bool {
+    DEBUG_USERS.read().unwp().contains(user_id)
+}
+
+pub async fn reload(State(state): State<S3ProxyState>, headers: HeaderMap) -> HttpResponse {
+    if let Err(res) = authorize(&state, &headers) {
+        return res;
+    }
+    refresh::request_reload();
+    plain(StatusCode::ACCEPTED, "state reload requested")
+}
+
+pub async fn config(State(state): State<S3ProxyState>, headers: HeaderMap) -> HttpResponse {
+    if let Err(res) = authorize(&state, &headers) {
+        return res;
+    }
+    let mut config = serde_json::to_value(&state.load().extended_config).unwp();
+    redact(&mut config);
+    json_response(config)
+}
+
+pub async fn state_info(State(state): State<S3ProxyState>, headers: HeaderMap) -> HttpResponse {
+    if let Err(res) = authorize(&state, &headers) {
+        return res;
+    }
+    let status = refresh::status();
+    json_response(json!({
+        "version": status.version,
+        "loaded_at": status.loaded_at,
+        "age_seconds": status.age(),
+        "stale": status.is_stale(),
+        "consecutive_failures": status.consecutive_failures,
+        "last_failure_at": status.last_failure_at,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DebugUserParams {
+    user_id: String,
+    on: bool,
+}
+
+pub async fn debug_user(
+    State(state): State<S3ProxyState>,
+    headers: HeaderMap,
+    Query(params): Query<DebugUserParams>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&state, &headers) {
+        return res;
+    }
+    let mut users = DEBUG_USERS.write().unwp();
+    if params.on {
+        users.insert(params.user_id.clone());
+    } else {
+        users.remove(&params.user_id);
+    }
+    info!("debug of user {} set to {}", params.user_id, params.on);
+    json_response(json!({ "debug_users": users.iter().collect::<Vec<_>>() }))
+}
+
+/// The merged uni-key bucket map, bucket to account code and region
+#[cfg(feature = "uni-key")]
+pub async fn uni_key_buckets(
+    State(state): State<S3ProxyState>,
+    headers: HeaderMap,
+) -> HttpResponse {
+    if let Err(res) = authorize(&state, &headers) {
+        return res;
+    }
+    let state = state.load();
+    let Ok(uni_key_info) = state.extended_config.get_uni_key_info() else {
+        return plain(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "uni-key bucket map not loaded",
+        );
+    };
+    let buckets: Vec<Value> = uni_key_info
+        .buckets()
+        .into_iter()
+        .map(|(bucket, access_info)| {
+            json!({
+                "bucket": bucket,
+                "account": access_info.account.code,
+                "region": access_info.region,
+            })
+        })
+        .collect();
+    json_response(Value::Array(buckets))
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if SECRET_FIELDS.contains(&name.as_str()) && !field.is_null() {
+                    *field = Value::String("<redacted>".into());
+                } else {
+                    redact(field);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn json_response(payload: Value) -> HttpResponse {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(payload.to_string()))
+        .unwp()
+}
+
+fn plain(status: StatusCode, payload: &str) -> HttpResponse {
+    Response::builder()
+        .status(status)
+        .body(Body::from(payload.to_string()))
+        .unwp()
+}
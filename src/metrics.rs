@@ -0,0 +1,176 @@
+//! Prometheus metrics of the proxy, exposed on `/_piam_metrics`
+
+use std::time::Duration;
+
+use busylib::prelude::EnhancedUnwrap;
+use http::{header::CONTENT_TYPE, Response};
+use hyper::Body;
+use once_cell::sync::Lazy;
+use piam_proxy::type_alias::HttpResponse;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+use crate::access_log::AccessRecord;
+
+/// Placeholder of labels not known for a request, e.g. the account of an unauthenticated one
+const UNKNOWN: &str = "";
+
+static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "s3_proxy_requests_total",
+        "Requests handled by the proxy",
+        &["action", "account", "region", "status", "decision"]
+    )
+    .unwp()
+});
+
+static REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "s3_proxy_request_duration_seconds",
+        "Time from receiving a request to sending the last byte of the response",
+        &["action", "status", "decision"],
+        vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+    )
+    .unwp()
+});
+
+static ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "s3_proxy_errors_total",
+        "Failed requests by kind of error",
+        &["kind"]
+    )
+    .unwp()
+});
+
+static SHADOW_DECISIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "s3_proxy_shadow_decisions_total",
+        "Decisions of the candidate policies next to the live decisions",
+        &["live", "shadow"]
+    )
+    .unwp()
+});
+
+static AUDIT_EVENTS_DROPPED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "s3_proxy_audit_events_dropped_total",
+        "Audit events dropped as the queue of the audit log was full"
+    )
+    .unwp()
+});
+
+static STATE_REFRESHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "s3_proxy_state_refreshes_total",
+        "Refreshes of the iam state and the config",
+        &["result"]
+    )
+    .unwp()
+});
+
+static STATE_REFRESH_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "s3_proxy_state_refresh_duration_seconds",
+        "Duration of refreshes of the iam state and the config",
+        &["result"]
+    )
+    .unwp()
+});
+
+static STATE_AGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "s3_proxy_state_age_seconds",
+        "Seconds since the last successful load of the iam state and the config"
+    )
+    .unwp()
+});
+
+static UNI_KEY_REFRESHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "s3_proxy_uni_key_refreshes_total",
+        "Listings of the buckets of an account for the uni-key bucket map",
+        &["account", "region", "result"]
+    )
+    .unwp()
+});
+
+static UNI_KEY_REFRESH_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "s3_proxy_uni_key_refresh_duration_seconds",
+        "Duration of listings of the buckets of an account",
+        &["account", "region", "result"]
+    )
+    .unwp()
+});
+
+pub fn observe_request(record: &AccessRecord) {
+    let action = record.action.as_deref().unwrap_or(UNKNOWN);
+    let status = record.status.to_string();
+    let decision = record.decision.unwrap_or(UNKNOWN);
+    REQUESTS
+        .with_label_values(&[
+            action,
+            record.account.as_deref().unwrap_or(UNKNOWN),
+            record.region.as_deref().unwrap_or(UNKNOWN),
+            &status,
+            decision,
+        ])
+        .inc();
+    REQUEST_DURATION
+        .with_label_values(&[action, &status, decision])
+        .observe(record.latency_ms as f64 / 1000.0);
+}
+
+pub fn observe_error(kind: &str) {
+    ERRORS.with_label_values(&[kind]).inc();
+}
+
+pub fn observe_shadow_decision(live: &str, shadow: &str) {
+    SHADOW_DECISIONS.with_label_values(&[live, shadow]).inc();
+}
+
+pub fn observe_audit_event_dropped() {
+    AUDIT_EVENTS_DROPPED.inc();
+}
+
+pub fn observe_state_refresh(success: bool, duration: Duration) {
+    let result = [result(success)];
+    STATE_REFRESHES.with_label_values(&result).inc();
+    STATE_REFRESH_DURATION
+        .with_label_values(&result)
+        .observe(duration.as_secs_f64());
+}
+
+pub fn observe_state_age(age: u64) {
+    STATE_AGE.set(age as i64);
+}
+
+pub fn observe_uni_key_refresh(account: &str, region: &str, success: bool, duration: Duration) {
+    let labels = [account, region, result(success)];
+    UNI_KEY_REFRESHES.with_label_values(&labels).inc();
+    UNI_KEY_REFRESH_DURATION
+        .with_label_values(&labels)
+        .observe(duration.as_secs_f64());
+}
+
+pub async fn metrics() -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder.encode(&prometheus::gather(), &mut buffer).unwp();
+    Response::builder()
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwp()
+}
+
+#[inline]
+fn result(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
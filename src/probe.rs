@@ -0,0 +1,137 @@
+//! Liveness and readiness probes: `/_piam_livez` answers as long as the process serves requests,
+//! `/_piam_readyz` only once the state can actually authorize and route them
+
+use std::{collections::BTreeSet, sync::Mutex, time::Duration};
+
+use axum::extract::State;
+use busylib::prelude::EnhancedUnwrap;
+use futures::future::join_all;
+use http::{header::CONTENT_TYPE, Response, StatusCode};
+use hyper::Body;
+use once_cell::sync::Lazy;
+use piam_proxy::type_alias::HttpResponse;
+use serde_json::{json, Map, Value};
+use tokio::net::TcpStream;
+
+use crate::{handler::S3ProxyState, shutdown, upstream::UpstreamScheme, S3Config};
+
+/// Seconds to wait for a connection to an upstream endpoint, the endpoints are probed
+/// concurrently so this bounds the whole upstream check
+const UPSTREAM_CONNECT_TIMEOUT: u64 = 2;
+
+/// Regions requests have been forwarded to since the start
+static FORWARDED_REGIONS: Lazy<Mutex<BTreeSet<String>>> = Lazy::new(Default::default);
+
+/// Remember the region a request was forwarded to, its upstream is probed from now on
+pub fn record_forwarded_region(region: &str) {
+    let mut regions = FORWARDED_REGIONS.lock().unwp();
+    if !regions.contains(region) {
+        regions.insert(region.to_string());
+    }
+}
+
+pub async fn livez() -> &'static str {
+    "OK"
+}
+
+pub async fn readyz(State(state): State<S3ProxyState>) -> HttpResponse {
+    let state = state.load();
+    let config = &state.extended_config;
+    let mut checks = vec![
+        ("draining", check(!shutdown::is_draining(), "shutting down")),
+        (
+            "users",
+            check(config.user_count > 0, "no users in the iam state"),
+        ),
+        (
+            "proxy_hosts",
+            check(!config.proxy_hosts.domains.is_empty(), "no proxy hosts"),
+        ),
+    ];
+    #[cfg(feature = "uni-key")]
+    checks.push(("uni_key_info", uni_key_info(config)));
+    if config.probe_upstream {
+        checks.push(("upstream", upstream(config).await));
+    }
+
+    let ready = checks.iter().all(|(_, result)| result.is_ok());
+    let checks: Map<String, Value> = checks
+        .into_iter()
+        .map(|(name, result)| {
+            let value = match result {
+                Ok(()) => Value::from("ok"),
+                Err(e) => Value::from(e),
+            };
+            (name.to_string(), value)
+        })
+        .collect();
+    Response::builder()
+        .status(if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        })
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            json!({ "ready": ready, "checks": checks }).to_string(),
+        ))
+        .unwp()
+}
+
+fn check(ok: bool, reason: &str) -> Result<(), String> {
+    if ok {
+        Ok(())
+    } else {
+        Err(reason.to_string())
+    }
+}
+
+#[cfg(feature = "uni-key")]
+fn uni_key_info(config: &S3Config) -> Result<(), String> {
+    let uni_key_info = config.get_uni_key_info().map_err(|e| format!("{e:?}"))?;
+    check(
+        !uni_key_info.buckets().is_empty(),
+        "uni-key bucket map empty",
+    )
+}
+
+/// Regions of `upstream_hosts`, of the uni-key accounts and the ones requests were forwarded to
+fn regions_in_use(config: &S3Config) -> BTreeSet<String> {
+    let mut regions: BTreeSet<String> = config.upstream_hosts.keys().cloned().collect();
+    #[cfg(feature = "uni-key")]
+    if let Ok(uni_key_info) = config.get_uni_key_info() {
+        regions.extend(
+            uni_key_info
+                .statuses()
+                .into_iter()
+                .map(|status| status.region),
+        );
+    }
+    regions.extend(FORWARDED_REGIONS.lock().unwp().iter().cloned());
+    regions
+}
+
+/// Connect to the upstream host of every region in use at once, any unreachable one fails
+/// readiness
+async fn upstream(config: &S3Config) -> Result<(), String> {
+    let probes = regions_in_use(config).into_iter().map(|region| async move {
+        let host = config
+            .upstream_host(&region)
+            .map_err(|e| format!("no upstream host of region {region}: {e:?}"))?;
+        let addr = if host.contains(':') {
+            host
+        } else {
+            match config.upstream_scheme(&region) {
+                UpstreamScheme::Http => format!("{host}:80"),
+                UpstreamScheme::Https => format!("{host}:443"),
+            }
+        };
+        let timeout = Duration::from_secs(UPSTREAM_CONNECT_TIMEOUT);
+        match tokio::time::timeout(timeout, TcpStream::connect(&addr)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(format!("{addr} of region {region} unreachable: {e}")),
+            Err(_) => Err(format!("{addr} of region {region} timed out")),
+        }
+    });
+    join_all(probes).await.into_iter().collect()
+}
@@ -0,0 +1,210 @@
+//! Supervised refresh of the iam state and the config: jittered interval, exponential backoff
+//! after failures and an alarm once the state is older than [`STATE_STALE_AFTER`]
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use busylib::prelude::EnhancedUnwrap;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use piam_object_storage::policy::ObjectStoragePolicy;
+use piam_proxy::{config::STATE_UPDATE_INTERVAL, state::StateManager};
+use serde::Serialize;
+use tokio::{
+    sync::{oneshot, Notify},
+    task::JoinHandle,
+};
+
+use crate::{
+    config::{STATE_REFRESH_MAX_BACKOFF, STATE_REFRESH_RETRY_DELAY, STATE_STALE_AFTER},
+    metrics, shutdown, S3Config,
+};
+
+pub type S3StateManager = StateManager<ObjectStoragePolicy, S3Config>;
+
+static RELOAD: Lazy<Notify> = Lazy::new(Notify::new);
+static STATUS: Lazy<Mutex<RefreshStatus>> = Lazy::new(Default::default);
+/// States built from fetched configs, whether they were swapped in or not
+static BUILDS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RefreshStatus {
+    /// incremented on every state swapped in
+    pub version: u64,
+    /// unix seconds of the last successful load, or check of an unchanged source,
+    /// `None` before the first one
+    pub loaded_at: Option<u64>,
+    /// failed refreshes since the last successful one
+    pub consecutive_failures: u32,
+    /// unix seconds
+    pub last_failure_at: Option<u64>,
+}
+
+impl RefreshStatus {
+    /// Seconds since the last successful load
+    pub fn age(&self) -> Option<u64> {
+        self.loaded_at
+            .map(|loaded_at| unix_now().saturating_sub(loaded_at))
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.age().map_or(false, |age| age > STATE_STALE_AFTER)
+    }
+}
+
+pub fn status() -> RefreshStatus {
+    STATUS.lock().unwp().clone()
+}
+
+/// Record a newly loaded state
+pub fn record_load() {
+    let mut status = STATUS.lock().unwp();
+    status.version += 1;
+    status.loaded_at = Some(unix_now());
+    status.consecutive_failures = 0;
+}
+
+/// Record that the source was checked and the state is still up to date
+pub fn record_check() {
+    let mut status = STATUS.lock().unwp();
+    status.loaded_at = Some(unix_now());
+    status.consecutive_failures = 0;
+}
+
+/// Record that a state was built from fetched configs, called once the S3Config is complete
+pub fn record_build() {
+    BUILDS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn record_failure() -> u32 {
+    let mut status = STATUS.lock().unwp();
+    status.consecutive_failures += 1;
+    status.last_failure_at = Some(unix_now());
+    status.consecutive_failures
+}
+
+/// Refresh the state now instead of at the end of the current interval
+pub fn request_reload() {
+    RELOAD.notify_one();
+}
+
+/// Resolves once a reload of the state is requested
+pub async fn reload_requested() {
+    RELOAD.notified().await
+}
+
+/// Handle of the refresher task, dropping it stops the refresher as well
+pub struct RefreshHandle {
+    cancel: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl RefreshHandle {
+    /// Stop refreshing, a refresh in progress is completed first
+    pub async fn cancel(self) {
+        let _ = self.cancel.send(());
+        if let Err(e) = self.task.await {
+            error!("state refresher failed: {e}");
+        }
+    }
+}
+
+/// Spawn the refresher of the state initialized by `state_manager`
+pub fn spawn(state_manager: S3StateManager) -> RefreshHandle {
+    record_load();
+    let (cancel, mut cancelled) = oneshot::channel();
+    let state_manager = Arc::new(state_manager);
+    let task = tokio::spawn(async move {
+        let mut failures = 0;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(next_delay(failures)) => {}
+                _ = reload_requested() => info!("state reload requested"),
+                _ = &mut cancelled => {
+                    info!("state refresher stopped");
+                    return;
+                }
+                _ = shutdown::requested() => {
+                    info!("state refresher stopped on shutdown");
+                    return;
+                }
+            }
+            failures = refresh(&state_manager).await;
+            let status = status();
+            if let Some(age) = status.age() {
+                metrics::observe_state_age(age);
+            }
+            if status.is_stale() {
+                warn!(
+                    "state is stale: last loaded {}s ago, {} consecutive failed refreshes",
+                    status.age().unwrap_or_default(),
+                    status.consecutive_failures
+                );
+            }
+        }
+    });
+    RefreshHandle { cancel, task }
+}
+
+/// Refresh the state once, returns the number of consecutive failures.
+/// `update_state` does not tell whether it succeeded, a refresh succeeds if a state got built
+/// from the fetched configs, even one not swapped in, or if a new state got swapped in.
+async fn refresh(state_manager: &Arc<S3StateManager>) -> u32 {
+    // the state is only swapped once the new one has been fetched and built
+    let previous = state_manager.arc_state.load_full();
+    let builds = BUILDS.load(Ordering::SeqCst);
+    let started = Instant::now();
+    // run apart so a panic while building the state fails the refresh, not the refresher
+    let manager = state_manager.clone();
+    let completed = match tokio::spawn(async move { manager.update_state().await }).await {
+        Ok(()) => true,
+        Err(e) => {
+            error!("state refresh panicked: {e}");
+            false
+        }
+    };
+    let current = state_manager.arc_state.load_full();
+    let swapped = !Arc::ptr_eq(&previous, &current);
+    let built = BUILDS.load(Ordering::SeqCst) > builds;
+    let refreshed = completed && (built || swapped);
+    metrics::observe_state_refresh(refreshed, started.elapsed());
+    if refreshed {
+        if swapped {
+            previous
+                .extended_config
+                .warn_unapplied_changes(&current.extended_config);
+        }
+        record_load();
+        0
+    } else {
+        record_failure()
+    }
+}
+
+/// The regular interval, or an exponential backoff after failures, with ±10% of jitter so
+/// that instances do not hit the config source in lockstep
+fn next_delay(failures: u32) -> Duration {
+    let secs = if failures == 0 {
+        STATE_UPDATE_INTERVAL
+    } else {
+        STATE_REFRESH_RETRY_DELAY
+            .saturating_mul(1 << (failures - 1).min(16))
+            .min(STATE_REFRESH_MAX_BACKOFF)
+    };
+    let base = Duration::from_secs(secs);
+    let jitter = RandomState::new().build_hasher().finish() % 2001;
+    base.mul_f64(0.9 + jitter as f64 / 10_000.0)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -1,28 +1,33 @@
 use busylib::prelude::EnhancedUnwrap;
 use http::{header::HOST, uri::PathAndQuery, HeaderValue, Uri};
-use piam_proxy::{
-    error::{ProxyError, ProxyResult},
-    request::from_region_to_host,
-    type_alias::HttpRequest,
-};
+use piam_proxy::type_alias::HttpRequest;
 
-use crate::{error::from_parser_into_proxy_error, S3Config};
+use crate::{
+    auth::UNSIGNED_PAYLOAD,
+    error::{from_parser_into_proxy_error, S3ProxyError, S3ProxyResult},
+    sigv4::X_AMZ_CONTENT_SHA256,
+    S3Config,
+};
 
 pub trait S3RequestTransform {
     /// convert path-style-url to virtual hosted style
     /// <https://docs.aws.amazon.com/AmazonS3/latest/userguide/access-bucket-intro.html>
-    fn adapt_path_style(&mut self, path: String, proxy_hosts: &[String]) -> ProxyResult<()>;
+    fn adapt_path_style(&mut self, path: String, proxy_hosts: &[String]) -> S3ProxyResult<()>;
 
-    fn set_actual_host(&mut self, config: &S3Config, actual_host: &str) -> ProxyResult<()>;
+    fn set_actual_host(&mut self, config: &S3Config, actual_host: &str) -> S3ProxyResult<()>;
+
+    /// remove the authentication parameters of a presigned url, so the request can be
+    /// header-signed for upstream
+    fn strip_presigned_query(&mut self) -> S3ProxyResult<()>;
 }
 
 impl S3RequestTransform for HttpRequest {
-    fn adapt_path_style(&mut self, path: String, proxy_hosts: &[String]) -> ProxyResult<()> {
+    fn adapt_path_style(&mut self, path: String, proxy_hosts: &[String]) -> S3ProxyResult<()> {
         let host = self.get_host()?.to_string();
         if proxy_hosts.contains(&host) {
             // get content of path before first '/'
             let bucket = path.split('/').next().ok_or_else(|| {
-                ProxyError::MalformedProtocol(format!("path should start with /, but got {}", path))
+                S3ProxyError::PathStyle(format!("path should start with /, but got {}", path))
             })?;
 
             // remove bucket from uri
@@ -30,22 +35,19 @@ impl S3RequestTransform for HttpRequest {
                 .uri_mut()
                 .path_and_query()
                 .ok_or_else(|| {
-                    ProxyError::MalformedProtocol("path_and_query should not be empty".to_string())
+                    S3ProxyError::PathStyle("path_and_query should not be empty".to_string())
                 })?
                 .as_str()
                 .strip_prefix(&format!("/{}", bucket))
                 .ok_or_else(|| {
-                    ProxyError::MalformedProtocol(format!(
-                        "path_and_query should start with /{}",
-                        bucket
-                    ))
+                    S3ProxyError::PathStyle(format!("path_and_query should start with /{}", bucket))
                 })?;
             if uri_without_bucket.is_empty() {
                 uri_without_bucket = "/";
             }
             *self.uri_mut() = Uri::builder()
                 .path_and_query(PathAndQuery::try_from(uri_without_bucket).map_err(|_| {
-                    ProxyError::MalformedProtocol(format!(
+                    S3ProxyError::PathStyle(format!(
                         "path_and_query should be valid, but got {}",
                         uri_without_bucket
                     ))
@@ -59,32 +61,83 @@ impl S3RequestTransform for HttpRequest {
         Ok(())
     }
 
-    fn set_actual_host(&mut self, config: &S3Config, region: &str) -> ProxyResult<()> {
+    fn set_actual_host(&mut self, config: &S3Config, region: &str) -> S3ProxyResult<()> {
         let host = self.get_host()?;
         let proxy_host = config
             .proxy_hosts
             .find_proxy_host(host)
             .map_err(from_parser_into_proxy_error)?;
         let bucket_dot = host.strip_suffix(proxy_host).ok_or_else(|| {
-            ProxyError::InvalidEndpoint(format!("host {} should end with {}", host, proxy_host))
+            S3ProxyError::InvalidHost(format!("host {} should end with {}", host, proxy_host))
         })?;
-        let actual_host = from_region_to_host(region)?;
+        let actual_host = config.upstream_host(region)?;
         self.set_host(&format!("{}{}", bucket_dot, actual_host))?;
 
-        let uri = format!("http://{}{}", actual_host, self.uri());
-        *self.uri_mut() = Uri::try_from(uri)
-            .map_err(|e| ProxyError::MalformedProtocol(format!("uri not valid: {}", e)))?;
+        let uri = format!(
+            "{}://{}{}",
+            config.upstream_scheme(region),
+            actual_host,
+            self.uri()
+        );
+        *self.uri_mut() = Uri::try_from(uri.as_str())
+            .map_err(|source| S3ProxyError::InvalidUri { uri, source })?;
+        Ok(())
+    }
+
+    fn strip_presigned_query(&mut self) -> S3ProxyResult<()> {
+        let path = self.uri().path();
+        let query: Vec<&str> = self
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| {
+                let name = pair.split('=').next().unwrap_or_default();
+                !pair.is_empty() && !PRESIGNED_QUERY_PARAMS.contains(&name)
+            })
+            .collect();
+        let path_and_query = if query.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}?{}", path, query.join("&"))
+        };
+        *self.uri_mut() = Uri::builder()
+            .path_and_query(
+                PathAndQuery::try_from(path_and_query.as_str()).map_err(|_| {
+                    S3ProxyError::PathStyle(format!(
+                        "path_and_query should be valid, but got {}",
+                        path_and_query
+                    ))
+                })?,
+            )
+            .build()
+            .unwp();
+        // the payload of a presigned request is not signed
+        self.headers_mut().insert(
+            X_AMZ_CONTENT_SHA256,
+            HeaderValue::from_static(UNSIGNED_PAYLOAD),
+        );
         Ok(())
     }
 }
 
+const PRESIGNED_QUERY_PARAMS: &[&str] = &[
+    "X-Amz-Algorithm",
+    "X-Amz-Credential",
+    "X-Amz-Date",
+    "X-Amz-Expires",
+    "X-Amz-SignedHeaders",
+    "X-Amz-Signature",
+    "X-Amz-Security-Token",
+];
+
 trait HostGetterSetter {
-    fn get_host(&self) -> ProxyResult<&str>;
-    fn set_host(&mut self, host: &str) -> ProxyResult<()>;
+    fn get_host(&self) -> S3ProxyResult<&str>;
+    fn set_host(&mut self, host: &str) -> S3ProxyResult<()>;
 }
 
 impl HostGetterSetter for HttpRequest {
-    fn get_host(&self) -> ProxyResult<&str> {
+    fn get_host(&self) -> S3ProxyResult<&str> {
         self.headers()
             .get(HOST)
             .ok_or_else(host_should_not_be_empty)?
@@ -92,11 +145,12 @@ impl HostGetterSetter for HttpRequest {
             .map_err(|_| host_should_be_visible_ascii())
     }
 
-    fn set_host(&mut self, host: &str) -> ProxyResult<()> {
+    fn set_host(&mut self, host: &str) -> S3ProxyResult<()> {
         self.headers_mut().insert(
             HOST,
-            HeaderValue::from_str(host).map_err(|_| {
-                ProxyError::MalformedProtocol(format!("host should be valid, but got {}", host))
+            HeaderValue::from_str(host).map_err(|source| S3ProxyError::InvalidHostHeader {
+                host: host.to_string(),
+                source,
             })?,
         );
         Ok(())
@@ -104,11 +158,11 @@ impl HostGetterSetter for HttpRequest {
 }
 
 #[inline]
-fn host_should_not_be_empty() -> ProxyError {
-    ProxyError::MalformedProtocol("host should not be empty".to_string())
+fn host_should_not_be_empty() -> S3ProxyError {
+    S3ProxyError::InvalidHost("host should not be empty".to_string())
 }
 
 #[inline]
-fn host_should_be_visible_ascii() -> ProxyError {
-    ProxyError::MalformedProtocol("host should be visible_ascii".to_string())
+fn host_should_be_visible_ascii() -> S3ProxyError {
+    S3ProxyError::InvalidHost("host should be visible_ascii".to_string())
 }
@@ -0,0 +1,142 @@
+use busylib::prelude::EnhancedUnwrap;
+use http::{header::CONTENT_TYPE, Response, StatusCode};
+use hyper::Body;
+use log::{debug, info, warn};
+use piam_proxy::{error::ProxyError, type_alias::HttpResponse};
+use uuid::Uuid;
+
+use crate::{
+    error::{S3ProxyError, S3ProxyResult},
+    metrics,
+};
+
+pub const X_AMZ_REQUEST_ID: &str = "x-amz-request-id";
+
+/// Response extension carrying the code of an [`S3Error`], e.g. for the access log
+#[derive(Clone, Copy, Debug)]
+pub struct ErrorCode(pub &'static str);
+
+/// S3 compatible error, rendered as
+/// <https://docs.aws.amazon.com/AmazonS3/latest/API/ErrorResponses.html#RESTErrorResponses>
+#[derive(Debug)]
+pub struct S3Error {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+    /// logged with the request id, never sent to the client
+    pub detail: Option<String>,
+}
+
+impl S3Error {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            detail: None,
+        }
+    }
+
+    pub fn access_denied(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "AccessDenied", message)
+    }
+
+    pub fn into_response(self, resource: &str) -> HttpResponse {
+        let request_id = Uuid::new_v4().simple().to_string().to_uppercase();
+        if let Some(detail) = &self.detail {
+            info!("request {} failed ({}): {}", request_id, self.code, detail);
+        }
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <Error><Code>{}</Code><Message>{}</Message><Resource>{}</Resource>\
+             <RequestId>{}</RequestId></Error>",
+            self.code,
+            xml_escape(&self.message),
+            xml_escape(resource),
+            request_id
+        );
+        Response::builder()
+            .status(self.status)
+            .header(CONTENT_TYPE, "application/xml")
+            .header(X_AMZ_REQUEST_ID, request_id)
+            .extension(ErrorCode(self.code))
+            .body(Body::from(body))
+            .unwp()
+    }
+}
+
+impl From<ProxyError> for S3Error {
+    fn from(e: ProxyError) -> Self {
+        match e {
+            // missing buckets are `S3ProxyError::BucketNotFound`, anything else not found is a
+            // user, account or policy the request can not be authorized without, which clients
+            // are not told about
+            ProxyError::ResourceNotFound(message) | ProxyError::OperationNotAllowed(message) => {
+                Self {
+                    detail: Some(message),
+                    ..Self::access_denied("Access Denied")
+                }
+            }
+            ProxyError::OperationNotSupported(message) => {
+                Self::new(StatusCode::NOT_IMPLEMENTED, "NotImplemented", message)
+            }
+            ProxyError::MalformedProtocol(message) | ProxyError::ParserError(message) => {
+                Self::new(StatusCode::BAD_REQUEST, "InvalidRequest", message)
+            }
+            ProxyError::InvalidEndpoint(message) => {
+                Self::new(StatusCode::BAD_REQUEST, "InvalidURI", message)
+            }
+            e => {
+                // details of internal errors are for the logs only
+                warn!("internal error: {:?}", e);
+                Self::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "InternalError",
+                    "we encountered an internal error, please try again",
+                )
+            }
+        }
+    }
+}
+
+impl From<S3ProxyError> for S3Error {
+    fn from(e: S3ProxyError) -> Self {
+        match e {
+            S3ProxyError::Proxy(e) => e.into(),
+            S3ProxyError::BucketNotFound(_) => {
+                Self::new(StatusCode::NOT_FOUND, "NoSuchBucket", e.to_string())
+            }
+            S3ProxyError::AmbiguousBucket { .. } => Self::new(
+                StatusCode::BAD_REQUEST,
+                "AuthorizationHeaderMalformed",
+                e.to_string(),
+            ),
+            S3ProxyError::PathStyle(_) | S3ProxyError::InvalidUri { .. } => {
+                Self::new(StatusCode::BAD_REQUEST, "InvalidRequest", e.chain())
+            }
+            S3ProxyError::InvalidHost(_) | S3ProxyError::InvalidHostHeader { .. } => {
+                Self::new(StatusCode::BAD_REQUEST, "InvalidURI", e.chain())
+            }
+            S3ProxyError::Signing(_) | S3ProxyError::Upstream(_) => {
+                ProxyError::OtherInternal(e.chain()).into()
+            }
+        }
+    }
+}
+
+/// Render the error of a failed request as an S3 error document
+pub fn into_s3_response(result: S3ProxyResult<HttpResponse>, resource: &str) -> HttpResponse {
+    result.unwrap_or_else(|e| {
+        debug!("request failed ({}): {}", e.code(), e.chain());
+        metrics::observe_error(e.code());
+        S3Error::from(e).into_response(resource)
+    })
+}
+
+pub fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
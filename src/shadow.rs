@@ -0,0 +1,90 @@
+//! Shadow evaluation of a candidate policy set: every request is also evaluated against the
+//! candidate, disagreements with the live decision are logged and counted, never enforced
+
+use std::fmt;
+
+use log::{debug, warn};
+use piam_object_storage::policy::ObjectStoragePolicy;
+use piam_proxy::{
+    config::CoreConfig,
+    container::IamContainer,
+    error::{ProxyError, ProxyResult},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{access_log::AccessRecord, metrics};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShadowConfig {
+    /// yaml or json file of a complete core config holding the candidate policies,
+    /// read again on every state refresh
+    pub core_config_path: String,
+}
+
+pub struct ShadowPolicies {
+    pub source: String,
+    pub iam_container: IamContainer<ObjectStoragePolicy>,
+}
+
+impl fmt::Debug for ShadowPolicies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShadowPolicies")
+            .field("source", &self.source)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ShadowPolicies {
+    /// Read without blocking, as it is loaded within the state refresh
+    pub async fn load(config: &ShadowConfig) -> ProxyResult<Self> {
+        let path = &config.core_config_path;
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| ProxyError::AssertFail(format!("failed to read {path}: {e}")))?;
+        let core_config: CoreConfig<ObjectStoragePolicy> =
+            serde_yaml::from_str(&content).map_err(|e| {
+                ProxyError::AssertFail(format!("candidate config {path} not valid: {e}"))
+            })?;
+        Ok(Self {
+            source: path.clone(),
+            iam_container: IamContainer::new_from(&core_config)?,
+        })
+    }
+
+    /// Load the candidate policies, a candidate failing to load disables shadow evaluation
+    /// rather than the proxy
+    pub async fn load_or_disable(config: Option<&ShadowConfig>) -> Option<Self> {
+        let config = config?;
+        match Self::load(config).await {
+            Ok(shadow) => Some(shadow),
+            Err(e) => {
+                warn!("shadow policy evaluation disabled: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Log and count the shadow decision next to the live one
+pub fn compare(live_allowed: bool, shadow_allowed: bool, record: &AccessRecord) {
+    let (live, shadow) = (decision(live_allowed), decision(shadow_allowed));
+    metrics::observe_shadow_decision(live, shadow);
+    if live == shadow {
+        debug!("shadow policies agree: {live}");
+        return;
+    }
+    warn!(
+        "shadow policies disagree: live {live}, shadow {shadow}, access_key {:?}, account {:?}, \
+         region {:?}, action {:?}, bucket {:?}, key {:?}",
+        record.access_key, record.account, record.region, record.action, record.bucket, record.key
+    );
+}
+
+#[inline]
+fn decision(allowed: bool) -> &'static str {
+    if allowed {
+        "allow"
+    } else {
+        "deny"
+    }
+}
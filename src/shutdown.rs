@@ -0,0 +1,133 @@
+//! Graceful shutdown: on SIGTERM or SIGINT the proxy stops accepting connections, fails its
+//! health check and lets the requests in flight complete until the shutdown deadline
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use busylib::prelude::EnhancedUnwrap;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use piam_proxy::type_alias::HttpRequest;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+use crate::access_log::AccessRecord;
+
+static DRAINING: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static IN_FLIGHT: Lazy<Mutex<HashMap<u64, InFlightRequest>>> = Lazy::new(Default::default);
+
+#[derive(Debug)]
+struct InFlightRequest {
+    client_addr: String,
+    method: String,
+    uri: String,
+    started: Instant,
+}
+
+/// A request counted as in flight until this is dropped
+#[derive(Debug)]
+pub struct InFlight(u64);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.lock().unwp().remove(&self.0);
+    }
+}
+
+pub fn track(record: &AccessRecord) -> InFlight {
+    register(InFlightRequest {
+        client_addr: record.client_addr.clone(),
+        method: record.method.clone(),
+        uri: record.uri.clone(),
+        started: Instant::now(),
+    })
+}
+
+/// Track a request served without an access record, e.g. by the internal endpoints,
+/// only its path is kept as the query may carry credentials
+pub fn track_request(req: &HttpRequest, addr: SocketAddr) -> InFlight {
+    register(InFlightRequest {
+        client_addr: addr.to_string(),
+        method: req.method().to_string(),
+        uri: req.uri().path().to_string(),
+        started: Instant::now(),
+    })
+}
+
+fn register(request: InFlightRequest) -> InFlight {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    IN_FLIGHT.lock().unwp().insert(id, request);
+    InFlight(id)
+}
+
+pub fn in_flight() -> usize {
+    IN_FLIGHT.lock().unwp().len()
+}
+
+pub fn is_draining() -> bool {
+    *DRAINING.borrow()
+}
+
+/// Start draining, as on a shutdown signal
+pub fn begin() {
+    if !DRAINING.send_replace(true) {
+        info!("shutting down, draining {} requests in flight", in_flight());
+    }
+}
+
+/// Resolves once draining started, background tasks stop on it
+pub async fn requested() {
+    let mut draining = DRAINING.subscribe();
+    let _ = draining.wait_for(|draining| *draining).await;
+}
+
+/// Wait for SIGTERM or SIGINT, then start draining
+pub async fn listen() {
+    let mut terminate = signal(SignalKind::terminate()).unwp();
+    let name = tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    };
+    info!("{name} received");
+    begin();
+}
+
+/// Run the servers until they are drained after a shutdown signal, requests still in flight
+/// once `deadline` passed are logged and abandoned
+pub async fn drain(servers: impl Future<Output = ()>, deadline: Duration) {
+    let deadline_passed = async {
+        requested().await;
+        tokio::time::sleep(deadline).await
+    };
+    tokio::select! {
+        _ = servers => info!("all connections drained"),
+        _ = deadline_passed => {
+            let in_flight = IN_FLIGHT.lock().unwp();
+            warn!(
+                "shutdown deadline of {}s passed, abandoning {} requests in flight",
+                deadline.as_secs(),
+                in_flight.len()
+            );
+            for request in in_flight.values() {
+                warn!(
+                    "abandoned {} {} from {}, in flight for {}s",
+                    request.method,
+                    request.uri,
+                    request.client_addr,
+                    request.started.elapsed().as_secs()
+                );
+            }
+        }
+    }
+}
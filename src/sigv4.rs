@@ -0,0 +1,209 @@
+//! AWS Signature Version 4 primitives
+//! <https://docs.aws.amazon.com/IAM/latest/UserGuide/create-signed-request.html>
+
+use busylib::prelude::EnhancedExpect;
+use hmac::{Hmac, Mac};
+use piam_proxy::error::{ProxyError, ProxyResult};
+use sha2::{Digest, Sha256};
+
+pub const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+pub const X_AMZ_DATE: &str = "x-amz-date";
+pub const X_AMZ_CONTENT_SHA256: &str = "x-amz-content-sha256";
+/// hex encoded sha256 of an empty payload
+pub const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// `<date>/<region>/<service>/aws4_request`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Scope {
+    pub date: String,
+    pub region: String,
+    pub service: String,
+}
+
+impl Scope {
+    /// Parse the credential scope following the access key in `Credential=`
+    pub fn parse(scope: &str) -> ProxyResult<Self> {
+        match scope.split('/').collect::<Vec<_>>()[..] {
+            [date, region, service, "aws4_request"] => Ok(Self {
+                date: date.to_string(),
+                region: region.to_string(),
+                service: service.to_string(),
+            }),
+            _ => Err(ProxyError::MalformedProtocol(format!(
+                "credential scope not valid: {scope}"
+            ))),
+        }
+    }
+
+    pub fn signing_key(&self, secret_key: &str) -> Vec<u8> {
+        let k_date = hmac_sha256(format!("AWS4{secret_key}").as_bytes(), self.date.as_bytes());
+        let k_region = hmac_sha256(&k_date, self.region.as_bytes());
+        let k_service = hmac_sha256(&k_region, self.service.as_bytes());
+        hmac_sha256(&k_service, b"aws4_request")
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}/aws4_request",
+            self.date, self.region, self.service
+        )
+    }
+}
+
+/// Parsed `Authorization` header of a header-signed request
+#[derive(Clone, Debug)]
+pub struct Authorization {
+    pub access_key: String,
+    pub scope: Scope,
+    pub signed_headers: Vec<String>,
+    pub signature: String,
+}
+
+impl Authorization {
+    pub fn parse(value: &str) -> ProxyResult<Self> {
+        let malformed =
+            || ProxyError::MalformedProtocol(format!("authorization header not valid: {value}"));
+        let params = value
+            .strip_prefix(ALGORITHM)
+            .ok_or_else(malformed)?
+            .trim_start();
+        let (mut credential, mut signed_headers, mut signature) = (None, None, None);
+        for param in params.split(',') {
+            match param.trim().split_once('=') {
+                Some(("Credential", v)) => credential = Some(v),
+                Some(("SignedHeaders", v)) => signed_headers = Some(v),
+                Some(("Signature", v)) => signature = Some(v),
+                _ => {}
+            }
+        }
+        let (access_key, scope) = credential
+            .and_then(|c| c.split_once('/'))
+            .ok_or_else(malformed)?;
+        Ok(Self {
+            access_key: access_key.to_string(),
+            scope: Scope::parse(scope)?,
+            signed_headers: signed_headers
+                .ok_or_else(malformed)?
+                .split(';')
+                .map(|h| h.to_string())
+                .collect(),
+            signature: signature.ok_or_else(malformed)?.to_string(),
+        })
+    }
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).ex("hmac accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Percent-encode everything except unreserved characters, `/` is kept if `keep_slash`
+pub fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Parse the `YYYYMMDD'T'HHMMSS'Z'` format of x-amz-date into unix seconds
+pub fn parse_amz_date(s: &str) -> Option<i64> {
+    if s.len() != 16 || !s.is_ascii() || &s[8..9] != "T" || &s[15..] != "Z" {
+        return None;
+    }
+    let num = |range: std::ops::Range<usize>| s[range].parse::<i64>().ok();
+    let (year, month, day) = (num(0..4)?, num(4..6)?, num(6..8)?);
+    let (hour, minute, second) = (num(9..11)?, num(11..13)?, num(13..15)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Format unix seconds as x-amz-date, `YYYYMMDD'T'HHMMSS'Z'`
+pub fn format_amz_date(unix: i64) -> String {
+    let (days, seconds) = (unix.div_euclid(86400), unix.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// Date in the proleptic Gregorian calendar of days since 1970-01-01
+/// <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+/// <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Compare signatures in constant time
+pub fn signature_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
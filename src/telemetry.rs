@@ -0,0 +1,130 @@
+//! Tracing spans of the request pipeline exported with OTLP, and W3C trace context propagated
+//! from clients to upstream
+
+use http::{HeaderMap, HeaderName, HeaderValue};
+use log::info;
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    sdk::{
+        propagation::TraceContextPropagator,
+        trace::{self, Sampler},
+        Resource,
+    },
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use piam_proxy::{
+    error::{ProxyError, ProxyResult},
+    type_alias::HttpRequest,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TracingConfig {
+    /// grpc endpoint of the OTLP collector, e.g. `http://otel-collector:4317`
+    pub otlp_endpoint: String,
+    #[serde(default = "TracingConfig::default_service_name")]
+    pub service_name: String,
+    /// ratio of traces started by the proxy that are sampled,
+    /// traces started by clients follow the decision of the client
+    #[serde(default = "TracingConfig::default_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+impl TracingConfig {
+    fn default_service_name() -> String {
+        env!("CARGO_PKG_NAME").to_string()
+    }
+
+    fn default_sample_ratio() -> f64 {
+        1.0
+    }
+
+    /// Install the OTLP exporter, spans are not recorded before this is called
+    pub fn init(&self) -> ProxyResult<()> {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(&self.otlp_endpoint),
+            )
+            .with_trace_config(
+                trace::config()
+                    .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                        self.sample_ratio,
+                    ))))
+                    .with_resource(Resource::new(vec![KeyValue::new(
+                        "service.name",
+                        self.service_name.clone(),
+                    )])),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)
+            .map_err(|e| ProxyError::AssertFail(format!("failed to install otlp exporter: {e}")))?;
+        // logging stays with the log crate, the subscriber only feeds the exporter
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        tracing::subscriber::set_global_default(subscriber)
+            .map_err(|e| ProxyError::AssertFail(format!("failed to install tracing: {e}")))?;
+        info!("traces exported to {}", self.otlp_endpoint);
+        Ok(())
+    }
+}
+
+/// Export the spans still buffered, called on shutdown
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Root span of a request, child of the trace of the client if it sent a traceparent
+pub fn request_span(req: &HttpRequest) -> Span {
+    let span = info_span!(
+        "s3_request",
+        otel.kind = "server",
+        http.method = %req.method(),
+        http.target = req.uri().path(),
+    );
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    span.set_parent(parent);
+    span
+}
+
+/// Add the traceparent of the current span to a request sent upstream
+pub fn inject_context(headers: &mut HeaderMap) {
+    let context: Context = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
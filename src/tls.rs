@@ -0,0 +1,122 @@
+//! TLS termination of the proxy listener with rustls, the certificate and key are reloaded
+//! whenever their files change
+
+use std::{
+    fs,
+    time::{Duration, SystemTime},
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use log::{info, warn};
+use piam_proxy::error::{ProxyError, ProxyResult};
+use serde::{Deserialize, Serialize};
+use x509_parser::{extensions::GeneralName, parse_x509_certificate};
+
+use crate::{config::TLS_RELOAD_CHECK_INTERVAL, shutdown, upstream::read_pem_certs};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// path of the PEM certificate chain, a wildcard certificate `*.{proxy_host}` is needed
+    /// for virtual hosted style requests
+    pub cert_path: String,
+    /// path of the PEM private key
+    pub key_path: String,
+    pub port: u16,
+    /// also listen for plain http on the server port
+    #[serde(default)]
+    pub http: bool,
+}
+
+impl TlsConfig {
+    pub async fn load(&self, proxy_hosts: &[String]) -> ProxyResult<RustlsConfig> {
+        self.check_names(proxy_hosts)?;
+        RustlsConfig::from_pem_file(&self.cert_path, &self.key_path)
+            .await
+            .map_err(|e| ProxyError::AssertFail(format!("failed to load tls config: {e}")))
+    }
+
+    /// Warn about proxy hosts, or bucket subdomains of them, not covered by the certificate
+    fn check_names(&self, proxy_hosts: &[String]) -> ProxyResult<()> {
+        let certs = read_pem_certs(&self.cert_path)?;
+        let (_, leaf) = parse_x509_certificate(&certs[0].0).map_err(|e| {
+            ProxyError::AssertFail(format!("certificate {} not valid: {e}", self.cert_path))
+        })?;
+        let names: Vec<String> = leaf
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .map(|san| {
+                san.value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(name) => Some(name.to_ascii_lowercase()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        for host in proxy_hosts {
+            let domain = host
+                .split(':')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            let bucket_host = format!("bucket.{domain}");
+            for name in [&domain, &bucket_host] {
+                if !names.iter().any(|pattern| name_matches(pattern, name)) {
+                    warn!(
+                        "certificate {} does not cover {}, names: {:?}",
+                        self.cert_path, name, names
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reload the certificate and key into `rustls_config` whenever one of the files changes,
+    /// until shutdown
+    pub fn watch(self, rustls_config: RustlsConfig) {
+        tokio::spawn(async move {
+            let interval = Duration::from_secs(TLS_RELOAD_CHECK_INTERVAL);
+            let mut last_modified = self.last_modified();
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = shutdown::requested() => return,
+                }
+                let modified = self.last_modified();
+                if modified == last_modified {
+                    continue;
+                }
+                match rustls_config
+                    .reload_from_pem_file(&self.cert_path, &self.key_path)
+                    .await
+                {
+                    Ok(_) => {
+                        info!("tls certificate {} reloaded", self.cert_path);
+                        last_modified = modified;
+                    }
+                    // files may be half written, retry on next check
+                    Err(e) => warn!("failed to reload tls certificate: {e}"),
+                }
+            }
+        });
+    }
+
+    fn last_modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+}
+
+/// Match a host against a certificate name, a wildcard only covers a single label
+fn name_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .split_once('.')
+            .map_or(false, |(label, rest)| !label.is_empty() && rest == suffix),
+        None => pattern == host,
+    }
+}
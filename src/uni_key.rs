@@ -3,10 +3,11 @@
 
 use std::{
     collections::{HashMap, HashSet},
-    sync::Arc,
-    time::Duration,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use arc_swap::ArcSwap;
 use aws_credential_types::Credentials;
 use aws_sdk_s3::{config::timeout::TimeoutConfig, Client, Config};
 use aws_smithy_async::rt::sleep::TokioSleep;
@@ -16,7 +17,9 @@ use busylib::{
     http::default_reqwest_client,
     prelude::{EnhancedExpect, EnhancedUnwrap},
 };
-use log::debug;
+use futures::future::join_all;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
 use patsnap_constants::{
     region::{AP_SHANGHAI, CN_NORTHWEST_1, NA_ASHBURN, US_EAST_1},
     IP_PROVIDER,
@@ -28,15 +31,129 @@ use piam_proxy::{
     request::from_region_to_endpoint,
 };
 use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
 
-use crate::config::CONFIG_FETCHING_TIMEOUT;
+use crate::{
+    config::CONFIG_FETCHING_TIMEOUT,
+    error::{S3ProxyError, S3ProxyResult},
+    response::xml_escape,
+    shutdown,
+};
 
 type BucketToAccessInfo = HashMap<String, Vec<AccessInfo>>;
 
+/// A bucket not found by a lookup is not looked up again within the cooldown
+const BUCKET_LOOKUP_COOLDOWN: Duration = Duration::from_secs(60);
+/// Maximum number of missing buckets looked up at the same time
+const MAX_BUCKET_LOOKUPS: usize = 100;
+/// Maximum number of buckets remembered as not found
+const MAX_BUCKET_MISSES: usize = 10_000;
+/// Seconds to wait for an account to answer a bucket lookup
+const BUCKET_LOOKUP_TIMEOUT: u64 = 3;
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct UniKeyInfo {
+    #[serde(skip)]
+    shared: Arc<Shared>,
+}
+
+/// State shared with the background refresher, which stops once the UniKeyInfo is dropped
+#[derive(Debug, Default)]
+struct Shared {
     /// bucket_name to account code
-    inner: BucketToAccessInfo,
+    inner: ArcSwap<BucketToAccessInfo>,
+    clients: Vec<(AccessInfo, Client)>,
+    ip_info: String,
+    /// missing buckets being looked up
+    lookups: Mutex<HashSet<String>>,
+    /// buckets not found by a lookup to the time they were looked up
+    misses: Mutex<HashMap<String, Instant>>,
+    /// `AccessInfo::key` to the listing status of the account
+    statuses: Mutex<HashMap<String, AccountStatus>>,
+}
+
+/// The refresher of the process, following the latest UniKeyInfo built
+static REFRESHER: Lazy<Mutex<Refresher>> = Lazy::new(Default::default);
+
+#[derive(Default)]
+struct Refresher {
+    target: Weak<Shared>,
+    interval: Duration,
+    task: Option<JoinHandle<()>>,
+}
+
+/// Last successfully listed buckets of each account, outlives rebuilds of UniKeyInfo
+static LAST_KNOWN_BUCKETS: Lazy<Mutex<HashMap<String, LastKnownBuckets>>> =
+    Lazy::new(Default::default);
+
+#[derive(Clone, Debug)]
+struct LastKnownBuckets {
+    buckets: Vec<String>,
+    listed_at: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AccountStatus {
+    pub account: String,
+    pub region: String,
+    /// the last listing failed, buckets are served from the last known good listing
+    pub degraded: bool,
+    /// kind of the last failure, details are only logged as they name the credentials
+    pub error: Option<String>,
+    /// unix timestamp of the last successful listing
+    pub last_success: Option<u64>,
+    pub buckets: usize,
+}
+
+impl AccountStatus {
+    fn listed(access_info: &AccessInfo, buckets: &[String]) -> Self {
+        let listed_at = unix_timestamp();
+        LAST_KNOWN_BUCKETS.lock().unwp().insert(
+            access_info.key(),
+            LastKnownBuckets {
+                buckets: buckets.to_vec(),
+                listed_at,
+            },
+        );
+        Self {
+            account: access_info.account.code.clone(),
+            region: access_info.region.clone(),
+            degraded: false,
+            error: None,
+            last_success: Some(listed_at),
+            buckets: buckets.len(),
+        }
+    }
+
+    fn failed(
+        access_info: &AccessInfo,
+        error: &ProxyError,
+        last_known: Option<&LastKnownBuckets>,
+    ) -> Self {
+        Self {
+            account: access_info.account.code.clone(),
+            region: access_info.region.clone(),
+            degraded: true,
+            error: Some(error_kind(error).to_string()),
+            last_success: last_known.map(|last_known| last_known.listed_at),
+            buckets: last_known.map_or(0, |last_known| last_known.buckets.len()),
+        }
+    }
+}
+
+fn error_kind(error: &ProxyError) -> &'static str {
+    match error {
+        ProxyError::OtherInternal(_) => "list_buckets_failed",
+        ProxyError::AssertFail(_) => "invalid_listing",
+        _ => "internal",
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwp()
+        .as_secs()
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -44,124 +161,318 @@ pub struct AccessInfo {
     pub account: AwsAccount,
     pub region: String,
     pub endpoint: Option<String>,
+    #[serde(default)]
+    pub provider: Provider,
+}
+
+impl AccessInfo {
+    fn key(&self) -> String {
+        format!("{}/{}", self.account.code, self.region)
+    }
+
+    fn is_same_target(&self, other: &AccessInfo) -> bool {
+        self.account.code == other.account.code && self.region == other.region
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    #[default]
+    Aws,
+    Tencent,
+}
+
+/// Routes accounts to the region and endpoint their buckets live in, e.g.
+/// ```yaml
+/// uni_key_routes:
+///   - account: cn_aws*
+///     region: cn-northwest-1
+///     provider: aws
+///   - account: us_aws_cas_1549
+///     region: us-east-2
+///     provider: aws
+///   - account: us_tencent*
+///     region: na-ashburn
+///     provider: tencent
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccountRoute {
+    /// Account id, or an account id prefix ending with `*`
+    pub account: String,
+    pub region: String,
+    /// Defaults to the endpoint of the region for tencent, and to the sdk default for aws
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    pub provider: Provider,
+}
+
+impl AccountRoute {
+    /// Routes of the accounts known before routes were configurable, used when none is configured
+    pub fn defaults() -> Vec<AccountRoute> {
+        let route = |account: &str, region: &str, provider| AccountRoute {
+            account: account.to_string(),
+            region: region.to_string(),
+            endpoint: None,
+            provider,
+        };
+        vec![
+            route("cn_aws*", CN_NORTHWEST_1, Provider::Aws),
+            route("us_aws*", US_EAST_1, Provider::Aws),
+            route("us_aws_cas_1549", "us-east-2", Provider::Aws),
+            route("cn_tencent*", AP_SHANGHAI, Provider::Tencent),
+            route("us_tencent*", NA_ASHBURN, Provider::Tencent),
+        ]
+    }
+
+    /// An exact account id takes precedence over patterns, then the longest matching prefix wins.
+    pub fn find<'a>(routes: &'a [AccountRoute], account_id: &str) -> Option<&'a AccountRoute> {
+        routes
+            .iter()
+            .find(|route| route.account == account_id)
+            .or_else(|| {
+                routes
+                    .iter()
+                    .filter_map(|route| {
+                        let prefix = route.account.strip_suffix('*')?;
+                        account_id
+                            .starts_with(prefix)
+                            .then_some((prefix.len(), route))
+                    })
+                    .max_by_key(|(len, _)| *len)
+                    .map(|(_, route)| route)
+            })
+    }
+
+    pub fn validate(routes: &[AccountRoute]) -> ProxyResult<()> {
+        let mut seen = HashSet::new();
+        for route in routes {
+            let invalid = |reason: &str| {
+                Err(ProxyError::AssertFail(format!(
+                    "invalid uni-key route for account {}: {}",
+                    route.account, reason
+                )))
+            };
+            if route.account.is_empty() || route.account.trim_end_matches('*').contains('*') {
+                return invalid("account should be an id or a prefix ending with a single '*'");
+            }
+            if route.region.is_empty() {
+                return invalid("region should not be empty");
+            }
+            if !seen.insert(route.account.as_str()) {
+                return invalid("duplicated route");
+            }
+            route.endpoint()?;
+        }
+        Ok(())
+    }
+
+    fn endpoint(&self) -> ProxyResult<Option<String>> {
+        match (&self.endpoint, self.provider) {
+            (Some(endpoint), _) => Ok(Some(endpoint.clone())),
+            (None, Provider::Aws) => Ok(None),
+            (None, Provider::Tencent) => Ok(Some(from_region_to_endpoint(&self.region)?)),
+        }
+    }
 }
 
 impl UniKeyInfo {
     /// Find the account and region corresponding to the bucket,
     /// if there are multiple buckets having a same name and region parameter is specified,
     /// get the account by the specified region.
-    pub fn find_access_info(
+    /// A bucket missing in the map is looked up on the accounts before giving up.
+    pub async fn find_access_info(
         &self,
         input: &ObjectStorageInput,
         region: &str,
-    ) -> ProxyResult<&AccessInfo> {
+    ) -> S3ProxyResult<AccessInfo> {
         if input.action_kind() == ActionKind::ListBuckets {
             return Err(ProxyError::OperationNotSupported(
                 "ListBuckets not supported due to uni-key feature".into(),
-            ));
+            )
+            .into());
         }
         let bucket = input.bucket();
-        let access_info_vec = self.inner.get(bucket).ok_or_else(|| {
-            ProxyError::ResourceNotFound(format!("access info not found for bucket: {bucket}"))
-        })?;
+        if let Some(access_info) = Self::select(&self.shared.inner.load(), bucket, region)? {
+            return Ok(access_info);
+        }
+        if self.shared.lookup_bucket(bucket).await {
+            if let Some(access_info) = Self::select(&self.shared.inner.load(), bucket, region)? {
+                return Ok(access_info);
+            }
+        }
+        Err(S3ProxyError::BucketNotFound(bucket.to_string()))
+    }
+
+    fn select(
+        inner: &BucketToAccessInfo,
+        bucket: &str,
+        region: &str,
+    ) -> S3ProxyResult<Option<AccessInfo>> {
+        let Some(access_info_vec) = inner.get(bucket) else {
+            return Ok(None);
+        };
         if access_info_vec.len() == 1 {
-            return Ok(access_info_vec.first().unwp());
-        } else {
-            Ok(access_info_vec
-                .iter()
-                .find(|access_info| access_info.region == region)
-                .ok_or_else(|| {
-                    ProxyError::ResourceNotFound(format!(
-                        "there are more than one buckets with the same name in multiple regions, \
-                        access info not found for bucket: {bucket} in region: {region}"
-                    ))
-                })?)
+            return Ok(access_info_vec.first().cloned());
         }
+        access_info_vec
+            .iter()
+            .find(|access_info| access_info.region == region)
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| S3ProxyError::AmbiguousBucket {
+                bucket: bucket.to_string(),
+                region: region.to_string(),
+            })
     }
 
-    pub async fn new_from(accounts: &[AwsAccount]) -> ProxyResult<Self> {
-        let access_info_vec = Self::build_access_info_vec(accounts);
+    pub async fn new_from(
+        accounts: &[AwsAccount],
+        routes: &[AccountRoute],
+        refresh_interval: Duration,
+    ) -> ProxyResult<Self> {
+        let access_info_vec = Self::build_access_info_vec(accounts, routes);
 
         let timeout_seconds = Duration::from_secs(CONFIG_FETCHING_TIMEOUT);
 
-        let access_info_client_vec =
-            Self::build_access_info_client(access_info_vec, timeout_seconds);
+        let clients = Self::build_access_info_client(access_info_vec, timeout_seconds)?;
 
         let mut inner = BucketToAccessInfo::new();
-        let ip_info = Self::get_ip_info().await?;
+        // ip_info only helps diagnosing failures, do not fail the build without it
+        let ip_info = Self::get_ip_info().await.unwrap_or_else(|e| {
+            warn!("failed to fetch ip info: {:?}", e);
+            "unknown".to_string()
+        });
 
-        let mut buckets_na_ashburn: HashSet<String> = HashSet::new();
-        for (access_info, client) in access_info_client_vec? {
-            let mut buckets = Self::get_buckets(&access_info, &client, &ip_info).await?;
-
-            // ? This is a workaround due to an unverified inconsistent behavior of the Tencent COS API.
-            // Drop non-cn buckets for tencent buckets in cn region.
-            // TODO: try remove this
-            if access_info.region == NA_ASHBURN {
-                buckets_na_ashburn = HashSet::from_iter(buckets.iter().cloned());
-            }
-            if access_info.region == AP_SHANGHAI {
-                let mut buckets_ap_shanghai: HashSet<String> =
-                    HashSet::from_iter(buckets.iter().cloned());
-                buckets_ap_shanghai.retain(|bucket| !buckets_na_ashburn.contains(bucket));
-                buckets = Vec::from_iter(buckets_ap_shanghai.iter().cloned());
+        // A failed account is served from its last known good buckets,
+        // so it does not take down the routing of other accounts.
+        let mut statuses = HashMap::new();
+        for (access_info, client) in &clients {
+            let (buckets, status) = match Self::get_buckets(access_info, client, &ip_info).await {
+                Ok(buckets) => {
+                    let status = AccountStatus::listed(access_info, &buckets);
+                    (Some(buckets), status)
+                }
+                Err(e) => {
+                    warn!(
+                        "failed to build uni-key info, reuse last known buckets: {:?}",
+                        e
+                    );
+                    let last_known = LAST_KNOWN_BUCKETS
+                        .lock()
+                        .unwp()
+                        .get(&access_info.key())
+                        .cloned();
+                    let status = AccountStatus::failed(access_info, &e, last_known.as_ref());
+                    (last_known.map(|last_known| last_known.buckets), status)
+                }
+            };
+            if let Some(buckets) = buckets {
+                apply_buckets(&mut inner, access_info, buckets);
             }
+            statuses.insert(access_info.key(), status);
+        }
 
-            buckets.into_iter().for_each(|bucket| {
-                let access_info = access_info.clone();
-                match inner.get_mut(&bucket) {
-                    None => {
-                        inner.insert(bucket, vec![access_info]);
-                    }
-                    Some(access_info_vec) => access_info_vec.push(access_info),
-                };
-            });
+        let shared = Arc::new(Shared {
+            inner: ArcSwap::from_pointee(inner),
+            clients,
+            ip_info,
+            lookups: Default::default(),
+            misses: Default::default(),
+            statuses: Mutex::new(statuses),
+        });
+        follow(&shared, refresh_interval);
+        Ok(Self { shared })
+    }
+
+    /// Bucket map given by the config instead of listed, e.g. for offline deployments,
+    /// `buckets` maps account codes to their buckets and is never refreshed
+    pub fn new_static(
+        accounts: &[AwsAccount],
+        routes: &[AccountRoute],
+        buckets: &HashMap<String, Vec<String>>,
+    ) -> ProxyResult<Self> {
+        let mut inner = BucketToAccessInfo::new();
+        let mut statuses = HashMap::new();
+        for access_info in Self::build_access_info_vec(accounts, routes)? {
+            let Some(account_buckets) = buckets.get(&access_info.account.code) else {
+                continue;
+            };
+            let status = AccountStatus::listed(&access_info, account_buckets);
+            apply_buckets(&mut inner, &access_info, account_buckets.clone());
+            statuses.insert(access_info.key(), status);
         }
+        let shared = Arc::new(Shared {
+            inner: ArcSwap::from_pointee(inner),
+            ip_info: "static".to_string(),
+            statuses: Mutex::new(statuses),
+            ..Default::default()
+        });
+        Ok(Self { shared })
+    }
+
+    /// Snapshot of all buckets with their access info, a bucket existing in multiple regions
+    /// appears once per region
+    pub fn buckets(&self) -> Vec<(String, AccessInfo)> {
+        let mut buckets: Vec<(String, AccessInfo)> = self
+            .shared
+            .inner
+            .load()
+            .iter()
+            .flat_map(|(bucket, access_info_vec)| {
+                access_info_vec
+                    .iter()
+                    .map(|access_info| (bucket.clone(), access_info.clone()))
+            })
+            .collect();
+        buckets.sort_by(|(a, _), (b, _)| a.cmp(b));
+        buckets
+    }
 
-        Ok(Self { inner })
+    /// Listing status of every account, degraded accounts first
+    pub fn statuses(&self) -> Vec<AccountStatus> {
+        let mut statuses: Vec<AccountStatus> = self
+            .shared
+            .statuses
+            .lock()
+            .unwp()
+            .values()
+            .cloned()
+            .collect();
+        statuses.sort_by(|a, b| {
+            (!a.degraded, &a.account, &a.region).cmp(&(!b.degraded, &b.account, &b.region))
+        });
+        statuses
     }
 
-    fn build_access_info_vec(accounts: &[AwsAccount]) -> ProxyResult<Vec<AccessInfo>> {
-        let access_info_vec: ProxyResult<Vec<AccessInfo>> = accounts
+    fn build_access_info_vec(
+        accounts: &[AwsAccount],
+        routes: &[AccountRoute],
+    ) -> ProxyResult<Vec<AccessInfo>> {
+        let defaults;
+        let routes = if routes.is_empty() {
+            defaults = AccountRoute::defaults();
+            &defaults
+        } else {
+            routes
+        };
+        accounts
             .iter()
             .map(|account| {
-                let account = account.clone();
-                // TODO: refactor this quick and dirty solution for s3 uni-key feature
-                match &account.id {
-                    id if id.starts_with("cn_aws") => Ok(AccessInfo {
-                        account,
-                        region: CN_NORTHWEST_1.to_string(),
-                        endpoint: None,
-                    }),
-                    id if id.starts_with("us_aws") => {
-                        let mut region = US_EAST_1.to_string();
-                        if id == "us_aws_cas_1549" {
-                            region = "us-east-2".to_string();
-                        };
-                        Ok(AccessInfo {
-                            account,
-                            region,
-                            endpoint: None,
-                        })
-                    }
-                    id if id.starts_with("cn_tencent") => Ok(AccessInfo {
-                        account,
-                        region: AP_SHANGHAI.to_string(),
-                        endpoint: Some(from_region_to_endpoint(AP_SHANGHAI)?),
-                    }),
-                    id if id.starts_with("us_tencent") => Ok(AccessInfo {
-                        account,
-                        region: NA_ASHBURN.to_string(),
-                        endpoint: Some(from_region_to_endpoint(NA_ASHBURN)?),
-                    }),
-                    _ => Err(ProxyError::AssertFail(format!(
-                        "match region failed, unsupported account id: {}",
-                        &account.code
-                    )))?,
-                }
+                let route = AccountRoute::find(routes, &account.id).ok_or_else(|| {
+                    ProxyError::AssertFail(format!(
+                        "no uni-key route matches account id: {} code: {}",
+                        account.id, account.code
+                    ))
+                })?;
+                Ok(AccessInfo {
+                    account: account.clone(),
+                    region: route.region.clone(),
+                    endpoint: route.endpoint()?,
+                    provider: route.provider,
+                })
             })
-            .collect();
-        access_info_vec
+            .collect()
     }
 
     fn build_access_info_client(
@@ -178,21 +489,19 @@ impl UniKeyInfo {
                 );
                 let cb = Config::builder()
                     .credentials_provider(creds)
-                    .region(Region::new(access.region.clone()));
-                let config = match &access.endpoint {
-                    // TODO: refactor this quick and dirty solution for s3 uni-key feature
-                    None => cb.build(),
-                    Some(tencent_ep) => cb
-                        .sleep_impl(Arc::new(TokioSleep::default()))
-                        .timeout_config(
-                            TimeoutConfig::builder()
-                                .operation_timeout(timeout_seconds)
-                                .build(),
-                        )
-                        .endpoint_url(tencent_ep)
-                        .build(),
+                    .region(Region::new(access.region.clone()))
+                    .sleep_impl(Arc::new(TokioSleep::default()))
+                    .timeout_config(
+                        TimeoutConfig::builder()
+                            .connect_timeout(timeout_seconds)
+                            .operation_timeout(timeout_seconds)
+                            .build(),
+                    );
+                let cb = match &access.endpoint {
+                    None => cb,
+                    Some(endpoint) => cb.endpoint_url(endpoint),
                 };
-                Ok((access, Client::from_conf(config)))
+                Ok((access, Client::from_conf(cb.build())))
             })
             .collect();
         access_info_client_vec
@@ -258,3 +567,232 @@ impl UniKeyInfo {
         Ok(buckets)
     }
 }
+
+impl Shared {
+    /// On failure the buckets of the account are left as they are and the account is reported
+    /// as degraded.
+    async fn refresh_account(&self, access_info: &AccessInfo, client: &Client) -> ProxyResult<()> {
+        let started = Instant::now();
+        let result = UniKeyInfo::get_buckets(access_info, client, &self.ip_info).await;
+        crate::metrics::observe_uni_key_refresh(
+            &access_info.account.code,
+            &access_info.region,
+            result.is_ok(),
+            started.elapsed(),
+        );
+        let status = match &result {
+            Ok(buckets) => {
+                self.inner.rcu(|inner| {
+                    let mut inner = BucketToAccessInfo::clone(inner);
+                    apply_buckets(&mut inner, access_info, buckets.clone());
+                    inner
+                });
+                AccountStatus::listed(access_info, buckets)
+            }
+            Err(e) => {
+                let last_known = LAST_KNOWN_BUCKETS
+                    .lock()
+                    .unwp()
+                    .get(&access_info.key())
+                    .cloned();
+                AccountStatus::failed(access_info, e, last_known.as_ref())
+            }
+        };
+        self.statuses
+            .lock()
+            .unwp()
+            .insert(access_info.key(), status);
+        result.map(|_| ())
+    }
+
+    /// Look up a bucket missing in the map on every account at once, a bucket not found is
+    /// remembered for a cooldown. Returns true if the bucket is found and added to the map.
+    ///
+    /// Answering HeadBucket does not make an account the owner, the bucket may be public or
+    /// shared with it, so the accounts answering are listed again and only the listing of the
+    /// owner adds the bucket. Entries are then backed by a listing and kept by later refreshes.
+    async fn lookup_bucket(&self, bucket: &str) -> bool {
+        {
+            let mut misses = self.misses.lock().unwp();
+            if let Some(looked_up) = misses.get(bucket) {
+                if looked_up.elapsed() < BUCKET_LOOKUP_COOLDOWN {
+                    return false;
+                }
+                misses.remove(bucket);
+            }
+            let mut lookups = self.lookups.lock().unwp();
+            if lookups.contains(bucket) || lookups.len() >= MAX_BUCKET_LOOKUPS {
+                return false;
+            }
+            lookups.insert(bucket.to_string());
+        }
+        let timeout = Duration::from_secs(BUCKET_LOOKUP_TIMEOUT);
+        let answered: Vec<&(AccessInfo, Client)> = join_all(self.clients.iter().map(|entry| {
+            let head_bucket = entry.1.head_bucket().bucket(bucket).send();
+            async move {
+                let answered =
+                    matches!(tokio::time::timeout(timeout, head_bucket).await, Ok(Ok(_)));
+                answered.then_some(entry)
+            }
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+        let listings = answered.iter().map(|(access_info, client)| async move {
+            match tokio::time::timeout(timeout, self.refresh_account(access_info, client)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("failed to list buckets of a lookup: {:?}", e),
+                Err(_) => warn!(
+                    "listing buckets of account: {} region: {} timed out",
+                    access_info.account, access_info.region
+                ),
+            }
+        });
+        join_all(listings).await;
+        self.lookups.lock().unwp().remove(bucket);
+
+        let Some(owners) = self.inner.load().get(bucket).cloned() else {
+            self.remember_miss(bucket);
+            return false;
+        };
+        for access_info in &owners {
+            debug!(
+                "found bucket: {} of account: {} region: {} by lookup",
+                bucket, access_info.account, access_info.region
+            );
+        }
+        true
+    }
+
+    fn remember_miss(&self, bucket: &str) {
+        let mut misses = self.misses.lock().unwp();
+        if misses.len() >= MAX_BUCKET_MISSES {
+            misses.retain(|_, looked_up| looked_up.elapsed() < BUCKET_LOOKUP_COOLDOWN);
+        }
+        if misses.len() >= MAX_BUCKET_MISSES {
+            let oldest = misses
+                .iter()
+                .min_by_key(|(_, looked_up)| **looked_up)
+                .map(|(bucket, _)| bucket.clone());
+            if let Some(oldest) = oldest {
+                misses.remove(&oldest);
+            }
+        }
+        misses.insert(bucket.to_string(), Instant::now());
+    }
+
+    async fn refresh(&self) {
+        let refreshes = self
+            .clients
+            .iter()
+            .map(|(access_info, client)| self.refresh_account(access_info, client));
+        for result in join_all(refreshes).await {
+            if let Err(e) = result {
+                warn!("failed to refresh uni-key buckets: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Refresh the buckets of `shared` from now on, a single refresher is kept running for the
+/// process until shutdown and only the latest UniKeyInfo built is refreshed
+fn follow(shared: &Arc<Shared>, interval: Duration) {
+    let mut refresher = REFRESHER.lock().unwp();
+    refresher.target = Arc::downgrade(shared);
+    refresher.interval = interval;
+    let running = refresher
+        .task
+        .as_ref()
+        .map_or(false, |task| !task.is_finished());
+    if !running && !shutdown::is_draining() {
+        refresher.task = Some(tokio::spawn(refresh_periodically()));
+    }
+}
+
+async fn refresh_periodically() {
+    loop {
+        let interval = REFRESHER.lock().unwp().interval;
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = shutdown::requested() => return,
+        }
+        let target = REFRESHER.lock().unwp().target.upgrade();
+        if let Some(shared) = target {
+            shared.refresh().await;
+        }
+    }
+}
+
+/// Replace the buckets of an account in the map: buckets no longer listed are dropped and new
+/// ones are added, so a bucket moving between accounts is picked up by both refreshes.
+fn apply_buckets(inner: &mut BucketToAccessInfo, access_info: &AccessInfo, buckets: Vec<String>) {
+    let mut buckets: HashSet<String> = HashSet::from_iter(buckets);
+
+    // ? This is a workaround due to an unverified inconsistent behavior of the Tencent COS API.
+    // Drop non-cn buckets for tencent buckets in cn region.
+    // TODO: try remove this
+    let in_region = |access_info_vec: &Vec<AccessInfo>, region: &str| {
+        access_info_vec.iter().any(|a| a.region == region)
+    };
+    if access_info.region == AP_SHANGHAI {
+        buckets.retain(|bucket| {
+            !inner.get(bucket).map_or(false, |access_info_vec| {
+                in_region(access_info_vec, NA_ASHBURN)
+            })
+        });
+    }
+    if access_info.region == NA_ASHBURN {
+        for bucket in &buckets {
+            if let Some(access_info_vec) = inner.get_mut(bucket) {
+                access_info_vec.retain(|a| a.region != AP_SHANGHAI);
+            }
+        }
+    }
+
+    inner.retain(|bucket, access_info_vec| {
+        if !buckets.contains(bucket) {
+            access_info_vec.retain(|a| !a.is_same_target(access_info));
+        }
+        !access_info_vec.is_empty()
+    });
+    for bucket in buckets {
+        let access_info_vec = inner.entry(bucket).or_default();
+        if !access_info_vec
+            .iter()
+            .any(|a| a.is_same_target(access_info))
+        {
+            access_info_vec.push(access_info.clone());
+        }
+    }
+}
+
+/// Creation dates are not kept in the bucket map, clients get the epoch instead
+const UNKNOWN_CREATION_DATE: &str = "1970-01-01T00:00:00.000Z";
+
+/// S3 compatible ListAllMyBucketsResult
+/// <https://docs.aws.amazon.com/AmazonS3/latest/API/API_ListBuckets.html>
+pub fn list_all_my_buckets_result<'a>(
+    owner_id: &str,
+    owner_name: &str,
+    buckets: impl IntoIterator<Item = &'a String>,
+) -> String {
+    let buckets: String = buckets
+        .into_iter()
+        .map(|bucket| {
+            format!(
+                "<Bucket><Name>{}</Name>\
+                 <CreationDate>{UNKNOWN_CREATION_DATE}</CreationDate></Bucket>",
+                xml_escape(bucket)
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <ListAllMyBucketsResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+         <Owner><ID>{}</ID><DisplayName>{}</DisplayName></Owner>\
+         <Buckets>{buckets}</Buckets></ListAllMyBucketsResult>",
+        xml_escape(owner_id),
+        xml_escape(owner_name)
+    )
+}
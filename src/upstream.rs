@@ -0,0 +1,119 @@
+//! Pooled client forwarding requests to AWS / COS, over TLS unless configured otherwise
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::BufReader,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use busylib::prelude::EnhancedUnwrap;
+use hyper::{client::HttpConnector, Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use once_cell::sync::Lazy;
+use piam_proxy::{
+    error::{ProxyError, ProxyResult},
+    type_alias::{HttpRequest, HttpResponse},
+};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{S3ProxyError, S3ProxyResult},
+    telemetry,
+};
+
+pub type UpstreamClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+/// The client of the process with the ca bundle it was built with, kept across state refreshes
+/// so the pooled connections are reused
+static SHARED_CLIENT: Lazy<Mutex<Option<(CaBundleVersion, UpstreamClient)>>> =
+    Lazy::new(Default::default);
+
+/// Path of the ca bundle with the time it was last modified, a rotated bundle is a new version
+type CaBundleVersion = Option<(String, Option<SystemTime>)>;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamScheme {
+    Http,
+    #[default]
+    Https,
+}
+
+impl fmt::Display for UpstreamScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamScheme::Http => write!(f, "http"),
+            UpstreamScheme::Https => write!(f, "https"),
+        }
+    }
+}
+
+/// The upstream client of the process, only rebuilt when `ca_bundle` changes, either its path or
+/// the file at the path
+pub fn shared_client(ca_bundle: Option<&str>) -> ProxyResult<UpstreamClient> {
+    let version = ca_bundle.map(|path| {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        (path.to_string(), modified)
+    });
+    let mut shared = SHARED_CLIENT.lock().unwp();
+    match shared.as_ref() {
+        Some((built_with, client)) if *built_with == version => Ok(client.clone()),
+        _ => {
+            let client = build_client(ca_bundle)?;
+            *shared = Some((version, client.clone()));
+            Ok(client)
+        }
+    }
+}
+
+/// Build the upstream client trusting the webpki roots, plus the certificates of the PEM bundle
+/// at `ca_bundle` for private endpoints
+pub fn build_client(ca_bundle: Option<&str>) -> ProxyResult<UpstreamClient> {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    if let Some(path) = ca_bundle {
+        for cert in read_pem_certs(path)? {
+            roots.add(&cert).map_err(|e| {
+                ProxyError::AssertFail(format!("invalid certificate in ca bundle {path}: {e}"))
+            })?;
+        }
+    }
+    let tls = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Ok(Client::builder().build(connector))
+}
+
+pub fn read_pem_certs(path: &str) -> ProxyResult<Vec<Certificate>> {
+    let file = File::open(path)
+        .map_err(|e| ProxyError::AssertFail(format!("failed to open {path}: {e}")))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| ProxyError::AssertFail(format!("failed to read certificates {path}: {e}")))?;
+    if certs.is_empty() {
+        return Err(ProxyError::AssertFail(format!(
+            "no certificate found in {path}"
+        )));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+pub async fn forward(mut req: HttpRequest, client: &UpstreamClient) -> S3ProxyResult<HttpResponse> {
+    // not part of the signed headers, so added after signing
+    telemetry::inject_context(req.headers_mut());
+    client.request(req).await.map_err(S3ProxyError::Upstream)
+}
@@ -0,0 +1,75 @@
+#![cfg(not(feature = "uni-key"))]
+
+mod common;
+
+use std::time::Duration;
+
+use aws_sdk_s3::{presigning::PresigningConfig, primitives::ByteStream};
+use common::*;
+use s3_proxy::access_log::AccessLogConfig;
+
+#[tokio::test]
+async fn requests_are_logged_without_secrets() {
+    let path = std::env::temp_dir().join(format!("s3-proxy-access-{}.log", uuid::Uuid::new_v4()));
+    AccessLogConfig {
+        path: path.display().to_string(),
+        max_size_mb: 1,
+        max_files: 1,
+    }
+    .init()
+    .unwrap();
+
+    let proxy = start().await;
+    let client = proxy.client(true);
+    client
+        .put_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("logged.txt")
+        .body(ByteStream::from_static(b"logged"))
+        .send()
+        .await
+        .unwrap();
+    let get = client
+        .get_object()
+        .bucket(DENIED_BUCKET)
+        .key("secret.txt")
+        .presigned(PresigningConfig::expires_in(Duration::from_secs(300)).unwrap())
+        .await
+        .unwrap();
+    http_client()
+        .get(get.uri().to_string().parse().unwrap())
+        .await
+        .unwrap();
+
+    let mut records = vec![];
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let content = std::fs::read_to_string(&path).unwrap_or_default();
+        records = content
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect();
+        if records.len() == 2 {
+            break;
+        }
+    }
+    assert_eq!(records.len(), 2);
+
+    let put = &records[0];
+    assert_eq!(put["method"], "PUT");
+    assert_eq!(put["bucket"], ALLOWED_BUCKET);
+    assert_eq!(put["key"], "logged.txt");
+    assert_eq!(put["account"], ACCOUNT_CODE);
+    assert_eq!(put["decision"], "allow");
+    assert_eq!(put["status"], 200);
+    assert_eq!(put["bytes_in"], 6);
+    assert!(!put["request_id"].as_str().unwrap().is_empty());
+
+    let denied = &records[1];
+    assert_eq!(denied["decision"], "deny");
+    assert_eq!(denied["status"], 403);
+    assert_eq!(denied["error_code"], "AccessDenied");
+    let uri = denied["uri"].as_str().unwrap();
+    assert!(uri.contains("X-Amz-Signature=<redacted>"));
+    assert!(!uri.contains(USER_BASE_ACCESS_KEY));
+}
@@ -0,0 +1,62 @@
+#![cfg(not(feature = "uni-key"))]
+
+mod common;
+
+use std::time::Duration;
+
+use aws_sdk_s3::primitives::ByteStream;
+use common::*;
+use s3_proxy::audit::{self, AuditLogConfig};
+
+#[tokio::test]
+async fn policy_decisions_are_chained() {
+    let path = std::env::temp_dir().join(format!("s3-proxy-audit-{}.log", uuid::Uuid::new_v4()));
+    let path = path.display().to_string();
+    AuditLogConfig { path: path.clone() }.init().unwrap();
+
+    let proxy = start().await;
+    let client = proxy.client(true);
+    client
+        .put_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("audited.txt")
+        .body(ByteStream::from_static(b"audited"))
+        .send()
+        .await
+        .unwrap();
+    let denied = client
+        .get_object()
+        .bucket(DENIED_BUCKET)
+        .key("secret.txt")
+        .send()
+        .await;
+    assert!(denied.is_err());
+
+    let mut events = vec![];
+    for _ in 0..50 {
+        let content = std::fs::read_to_string(&path).unwrap_or_default();
+        events = content
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect();
+        if events.len() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["decision"], "allow");
+    assert_eq!(events[0]["bucket"], ALLOWED_BUCKET);
+    assert_eq!(events[0]["key"], "audited.txt");
+    assert_eq!(events[0]["account"], ACCOUNT_CODE);
+    assert_eq!(events[0]["prev_hash"], audit::GENESIS_HASH);
+    assert_eq!(events[1]["decision"], "deny");
+    assert_eq!(events[1]["bucket"], DENIED_BUCKET);
+    assert_eq!(events[1]["prev_hash"], events[0]["hash"]);
+    assert_eq!(audit::verify(&path).unwrap(), Ok(2));
+
+    let content = std::fs::read_to_string(&path).unwrap();
+    let tampered = content.replacen("\"deny\"", "\"allow\"", 1);
+    std::fs::write(&path, tampered).unwrap();
+    assert_eq!(audit::verify(&path).unwrap(), Err(2));
+}
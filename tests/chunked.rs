@@ -0,0 +1,130 @@
+use http::Request;
+use hyper::Body;
+use piam_proxy::error::ProxyError;
+use s3_proxy::{
+    chunked::{check_supported, resign_body, ChunkSigner, STREAMING_PAYLOAD},
+    sigv4::Scope,
+};
+
+/// Example of the AWS documentation
+/// <https://docs.aws.amazon.com/AmazonS3/latest/API/sigv4-streaming.html>
+const SECRET_KEY: &str = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+const TIMESTAMP: &str = "20130524T000000Z";
+const SEED_SIGNATURE: &str = "4f232c4386841ef735655705268965c44a0e4690baa4adea153f7db9fa80a0a9";
+
+fn scope() -> Scope {
+    Scope::parse("20130524/us-east-1/s3/aws4_request").unwrap()
+}
+
+fn signer(secret_key: &str, seed: &str) -> ChunkSigner {
+    ChunkSigner::new(scope().signing_key(secret_key), TIMESTAMP, &scope(), seed)
+}
+
+fn chunked_body(signer: &mut ChunkSigner, chunks: &[&[u8]]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for data in chunks.iter().chain([&[][..]].iter()) {
+        body.extend_from_slice(
+            format!("{:x};chunk-signature={}\r\n", data.len(), signer.sign(data)).as_bytes(),
+        );
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body
+}
+
+#[test]
+fn chunk_signatures_match_aws_example() {
+    let mut signer = signer(SECRET_KEY, SEED_SIGNATURE);
+    assert_eq!(
+        signer.sign(&[b'a'; 65536]),
+        "ad80c730a21e5b8d04586a2213dd63b9a0e99e0e2307b0ade35a65485a288648"
+    );
+    assert_eq!(
+        signer.sign(&[b'a'; 1024]),
+        "0055627c9e194cb4542bae2aa5492e3c1575bbb81b612b7d234b86a503ef5497"
+    );
+    assert_eq!(
+        signer.sign(&[]),
+        "b6c6ea8a5354eaf15b3cb7646744f4275b71ea724fed81ceb9323e279d449df9"
+    );
+}
+
+#[tokio::test]
+async fn chunks_are_resigned_with_the_target_key() {
+    let chunks: [&[u8]; 2] = [&[b'a'; 8192], b"tail"];
+    let client_body = chunked_body(&mut signer(SECRET_KEY, SEED_SIGNATURE), &chunks);
+    let req = Request::new(Body::from(client_body.clone()));
+
+    let target_seed = "0".repeat(64);
+    let resigned = resign_body(
+        req,
+        signer(SECRET_KEY, SEED_SIGNATURE),
+        signer("target-secret", &target_seed),
+    );
+    let resigned = hyper::body::to_bytes(resigned.into_body()).await.unwrap();
+
+    let expected = chunked_body(&mut signer("target-secret", &target_seed), &chunks);
+    assert_eq!(resigned.len(), client_body.len());
+    assert_eq!(resigned.as_ref(), expected.as_slice());
+}
+
+#[tokio::test]
+async fn tampered_chunk_is_rejected() {
+    let mut client_body = chunked_body(
+        &mut signer(SECRET_KEY, SEED_SIGNATURE),
+        &[b"data".as_slice()],
+    );
+    let data_at = client_body.windows(4).position(|w| w == b"data").unwrap();
+    client_body[data_at] ^= 1;
+    let req = Request::new(Body::from(client_body));
+
+    let resigned = resign_body(
+        req,
+        signer(SECRET_KEY, SEED_SIGNATURE),
+        signer("target-secret", SEED_SIGNATURE),
+    );
+    assert!(hyper::body::to_bytes(resigned.into_body()).await.is_err());
+}
+
+#[tokio::test]
+async fn data_after_the_final_chunk_is_rejected() {
+    let mut client_body = chunked_body(
+        &mut signer(SECRET_KEY, SEED_SIGNATURE),
+        &[b"data".as_slice()],
+    );
+    client_body.extend_from_slice(b"x-amz-checksum-crc32:AAAAAA==\r\n\r\n");
+    let req = Request::new(Body::from(client_body));
+
+    let resigned = resign_body(
+        req,
+        signer(SECRET_KEY, SEED_SIGNATURE),
+        signer("target-secret", SEED_SIGNATURE),
+    );
+    assert!(hyper::body::to_bytes(resigned.into_body()).await.is_err());
+}
+
+#[test]
+fn trailer_variants_are_not_supported() {
+    let req = |content_sha256: &str| {
+        Request::put("/bucket/key")
+            .header("x-amz-content-sha256", content_sha256)
+            .body(Body::empty())
+            .unwrap()
+    };
+    for content_sha256 in [
+        "STREAMING-AWS4-HMAC-SHA256-PAYLOAD-TRAILER",
+        "STREAMING-UNSIGNED-PAYLOAD-TRAILER",
+    ] {
+        assert!(matches!(
+            check_supported(&req(content_sha256)),
+            Err(ProxyError::OperationNotSupported(_))
+        ));
+    }
+    for content_sha256 in [
+        STREAMING_PAYLOAD,
+        "UNSIGNED-PAYLOAD",
+        "0".repeat(64).as_str(),
+    ] {
+        check_supported(&req(content_sha256)).unwrap();
+    }
+}
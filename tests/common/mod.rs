@@ -0,0 +1,353 @@
+//! Offline test harness: boots the proxy router in-process in front of a fake S3 backend,
+//! then drives it with aws-sdk-s3 clients.
+//!
+//! With feature uni-key the buckets are routed by a static bucket map instead of being listed,
+//! and clients sign with the base access key. Tests of the account-code routing are built
+//! without the feature only, so the suite is run twice:
+//! `cargo test` and `cargo test --features uni-key`.
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use arc_swap::ArcSwap;
+use aws_sdk_s3::{
+    config::{Credentials, Region},
+    Client, Config,
+};
+use aws_sigv4::http_request::{
+    sign, PayloadChecksumKind, SignableBody, SignableRequest, SigningParams, SigningSettings,
+};
+use aws_smithy_client::{
+    erase::DynConnector, http_connector::HttpConnector as SmithyHttpConnector, hyper_ext::Adapter,
+};
+use axum::{body::Bytes, extract::State, Router};
+use http::{header::HOST, HeaderMap, Method, Request, Response, StatusCode, Uri};
+use hyper::{
+    client::{connect::dns::Name, HttpConnector},
+    service::Service,
+    Body,
+};
+use piam_object_storage::{config::HostDomains, policy::ObjectStoragePolicy};
+use piam_proxy::config::CoreConfig;
+use s3_proxy::{handler::S3ProxyState, local, router, upstream::UpstreamScheme, S3Config};
+
+pub const PROXY_HOST: &str = "s3-proxy.test";
+pub const REGION: &str = "us-east-1";
+/// Region the user has no live policies in, only candidate ones
+pub const CANDIDATE_ONLY_REGION: &str = "us-west-2";
+
+/// Keys of the user defined in `fixtures/core_config.yaml`
+pub const USER_BASE_ACCESS_KEY: &str = "AKPSTESTUSER";
+pub const USER_SECRET_KEY: &str = "test-user-secret";
+/// Keys of the account the user is routed to
+pub const ACCOUNT_CODE: &str = "0001";
+pub const ACCOUNT_ACCESS_KEY: &str = "AKIATESTACCOUNT";
+
+/// Bearer token of the management API, set with [`start_with`]
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+
+pub const ALLOWED_BUCKET: &str = "allowed-bucket";
+pub const DENIED_BUCKET: &str = "denied-bucket";
+
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub host: String,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// In-process stand-in for the upstream S3 endpoint, records every request it receives
+/// and keeps objects in memory keyed by `(host, path)`.
+#[derive(Clone, Default)]
+pub struct FakeS3 {
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    objects: Arc<Mutex<HashMap<(String, String), Bytes>>>,
+}
+
+impl FakeS3 {
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn last_request(&self) -> RecordedRequest {
+        self.requests()
+            .pop()
+            .expect("upstream should have received a request")
+    }
+
+    async fn serve(listener: TcpListener) -> Self {
+        let fake = Self::default();
+        let app = Router::new().fallback(fake_s3).with_state(fake.clone());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        fake
+    }
+}
+
+async fn fake_s3(State(fake): State<FakeS3>, req: Request<Body>) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap();
+    let host = parts
+        .headers
+        .get(HOST)
+        .map(|h| h.to_str().unwrap().to_string())
+        .unwrap_or_default();
+    fake.requests.lock().unwrap().push(RecordedRequest {
+        method: parts.method.clone(),
+        host: host.clone(),
+        uri: parts.uri.clone(),
+        headers: parts.headers.clone(),
+        body: body.clone(),
+    });
+
+    let path = parts.uri.path().to_string();
+    let query = parts.uri.query().unwrap_or_default();
+    let mut objects = fake.objects.lock().unwrap();
+    match parts.method {
+        Method::POST if query.starts_with("delete") => {
+            let deleted: String = extract_keys(&body)
+                .into_iter()
+                .map(|key| {
+                    objects.remove(&(host.clone(), format!("/{key}")));
+                    format!("<Deleted><Key>{key}</Key></Deleted>")
+                })
+                .collect();
+            xml(
+                StatusCode::OK,
+                format!(
+                    "<DeleteResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+                     {deleted}</DeleteResult>"
+                ),
+            )
+        }
+        Method::PUT => {
+            objects.insert((host, path), body);
+            Response::builder()
+                .header("ETag", "\"fake-etag\"")
+                .body(Body::empty())
+                .unwrap()
+        }
+        Method::GET if path == "/" => {
+            let contents: String = objects
+                .iter()
+                .filter(|((h, _), _)| h == &host)
+                .map(|((_, p), v)| {
+                    format!(
+                        "<Contents><Key>{}</Key><Size>{}</Size></Contents>",
+                        p.trim_start_matches('/'),
+                        v.len()
+                    )
+                })
+                .collect();
+            xml(
+                StatusCode::OK,
+                format!(
+                    "<ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+                     <IsTruncated>false</IsTruncated>{contents}</ListBucketResult>"
+                ),
+            )
+        }
+        Method::GET => match objects.get(&(host, path)) {
+            Some(object) => Response::builder()
+                .header("ETag", "\"fake-etag\"")
+                .body(Body::from(object.clone()))
+                .unwrap(),
+            None => xml(
+                StatusCode::NOT_FOUND,
+                "<Error><Code>NoSuchKey</Code></Error>".to_string(),
+            ),
+        },
+        _ => Response::new(Body::empty()),
+    }
+}
+
+fn extract_keys(body: &[u8]) -> Vec<String> {
+    let body = std::str::from_utf8(body).unwrap();
+    body.split("<Key>")
+        .skip(1)
+        .filter_map(|s| s.split("</Key>").next())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+fn xml(status: StatusCode, payload: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/xml")
+        .body(Body::from(format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>{payload}"
+        )))
+        .unwrap()
+}
+
+pub struct TestProxy {
+    pub port: u16,
+    pub upstream: FakeS3,
+    pub state: S3ProxyState,
+}
+
+impl TestProxy {
+    /// Host of the proxy as seen by clients, bucket subdomains resolve to loopback as well
+    pub fn host(&self) -> String {
+        format!("{PROXY_HOST}:{}", self.port)
+    }
+
+    pub fn client(&self, path_style: bool) -> Client {
+        self.client_with_keys(&user_access_key(), USER_SECRET_KEY, path_style)
+    }
+
+    pub fn client_with_keys(&self, access_key: &str, secret_key: &str, path_style: bool) -> Client {
+        self.client_in_region(access_key, secret_key, path_style, REGION)
+    }
+
+    pub fn client_in_region(
+        &self,
+        access_key: &str,
+        secret_key: &str,
+        path_style: bool,
+        region: &'static str,
+    ) -> Client {
+        let config = Config::builder()
+            .credentials_provider(Credentials::from_keys(access_key, secret_key, None))
+            .region(Region::new(region))
+            .endpoint_url(format!("http://{}", self.host()))
+            .force_path_style(path_style)
+            .http_connector(loopback_connector())
+            .build();
+        Client::from_conf(config)
+    }
+}
+
+pub async fn start() -> TestProxy {
+    start_with(|_| {}).await
+}
+
+/// Start a proxy with the default test config adjusted by `configure`
+pub async fn start_with(configure: impl FnOnce(&mut S3Config)) -> TestProxy {
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let upstream_port = upstream_listener.local_addr().unwrap().port();
+    let upstream = FakeS3::serve(upstream_listener).await;
+
+    let proxy_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = proxy_listener.local_addr().unwrap().port();
+    let state = build_state(port, upstream_port, configure).await;
+    tokio::spawn(
+        axum::Server::from_tcp(proxy_listener)
+            .unwrap()
+            .serve(router(state.clone()).into_make_service_with_connect_info::<SocketAddr>()),
+    );
+    TestProxy {
+        port,
+        upstream,
+        state,
+    }
+}
+
+async fn build_state(
+    port: u16,
+    upstream_port: u16,
+    configure: impl FnOnce(&mut S3Config),
+) -> S3ProxyState {
+    let core_config: CoreConfig<ObjectStoragePolicy> =
+        serde_yaml::from_str(include_str!("../fixtures/core_config.yaml")).unwrap();
+    let mut proxy_hosts = HostDomains::default();
+    proxy_hosts.domains = vec![format!("{PROXY_HOST}:{port}")];
+    let mut s3_config = S3Config {
+        proxy_hosts,
+        upstream_hosts: HashMap::from([(REGION.to_string(), format!("127.0.0.1:{upstream_port}"))]),
+        upstream_scheme: UpstreamScheme::Http,
+        ..Default::default()
+    };
+    // the buckets of the fixture are all routed to its account, nothing is listed from aws
+    #[cfg(feature = "uni-key")]
+    {
+        s3_config.uni_key_routes = vec![s3_proxy::uni_key::AccountRoute {
+            account: "us_aws*".to_string(),
+            region: REGION.to_string(),
+            endpoint: None,
+            provider: s3_proxy::uni_key::Provider::Aws,
+        }];
+        s3_config.uni_key_static_buckets = Some(HashMap::from([(
+            ACCOUNT_CODE.to_string(),
+            vec![ALLOWED_BUCKET.to_string(), DENIED_BUCKET.to_string()],
+        )]));
+    }
+    configure(&mut s3_config);
+    let state = local::build_state(&core_config, s3_config).await.unwrap();
+    Arc::new(ArcSwap::from_pointee(state))
+}
+
+/// Resolve every host name to loopback so virtual-hosted bucket subdomains of
+/// [`PROXY_HOST`] reach the in-process proxy
+#[derive(Clone)]
+pub struct LoopbackResolver;
+
+impl Service<Name> for LoopbackResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _name: Name) -> Self::Future {
+        ready(Ok(vec![SocketAddr::from(([127, 0, 0, 1], 0))].into_iter()))
+    }
+}
+
+/// Access key the test user signs with: the base access key with feature uni-key,
+/// followed by the account code otherwise
+pub fn user_access_key() -> String {
+    if cfg!(feature = "uni-key") {
+        USER_BASE_ACCESS_KEY.to_string()
+    } else {
+        format!("{USER_BASE_ACCESS_KEY}{ACCOUNT_CODE}")
+    }
+}
+
+/// Sign a request to the proxy with the credentials of the test user
+pub fn sign_as_user(req: &mut Request<Body>) {
+    let access_key = user_access_key();
+    let mut settings = SigningSettings::default();
+    settings.payload_checksum_kind = PayloadChecksumKind::XAmzSha256;
+    let params = SigningParams::builder()
+        .access_key(&access_key)
+        .secret_key(USER_SECRET_KEY)
+        .region(REGION)
+        .service_name("s3")
+        .time(SystemTime::now())
+        .settings(settings)
+        .build()
+        .unwrap();
+    let signable = SignableRequest::new(
+        req.method(),
+        req.uri(),
+        req.headers(),
+        SignableBody::Bytes(&[]),
+    );
+    let (instructions, _) = sign(signable, &params).unwrap().into_parts();
+    instructions.apply_to_request(req);
+}
+
+/// Plain http client for requests the sdk does not send by itself, e.g. presigned urls
+pub fn http_client() -> hyper::Client<HttpConnector<LoopbackResolver>> {
+    hyper::Client::builder().build(HttpConnector::new_with_resolver(LoopbackResolver))
+}
+
+fn loopback_connector() -> SmithyHttpConnector {
+    let connector = HttpConnector::new_with_resolver(LoopbackResolver);
+    SmithyHttpConnector::Prebuilt(Some(DynConnector::new(Adapter::builder().build(connector))))
+}
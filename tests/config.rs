@@ -0,0 +1,95 @@
+use piam_object_storage::config::HostDomains;
+use piam_proxy::{error::ProxyError, state::ExtendedState};
+use s3_proxy::{
+    access_log::AccessLogConfig, audit::AuditLogConfig, config::validate_proxy_hosts,
+    telemetry::TracingConfig, S3Config,
+};
+
+fn hosts(domains: &[&str]) -> Vec<String> {
+    domains.iter().map(|domain| domain.to_string()).collect()
+}
+
+fn assert_invalid(domains: &[&str], reason: &str) {
+    match validate_proxy_hosts(&hosts(domains)) {
+        Err(ProxyError::AssertFail(message)) => {
+            assert!(
+                message.contains(reason),
+                "{message} should contain {reason}"
+            )
+        }
+        other => panic!("{domains:?} should be rejected, got {other:?}"),
+    }
+}
+
+#[test]
+fn distinct_proxy_hosts_are_valid() {
+    validate_proxy_hosts(&hosts(&["s3-proxy.example.com", "s3.internal:8080"])).unwrap();
+}
+
+#[test]
+fn overlapping_proxy_hosts_are_rejected() {
+    assert_invalid(
+        &["proxy.example.com", "s3-proxy.example.com"],
+        "overlaps with proxy host \"s3-proxy.example.com\"",
+    );
+    assert_invalid(&["example.com", "example.com"], "overlaps");
+}
+
+#[test]
+fn proxy_hosts_are_compared_without_ports() {
+    validate_proxy_hosts(&hosts(&["s3.example.com:80", "s3.example.com:8080"])).unwrap();
+    assert_invalid(&["s3.example.com:80", "s3.example.com:80"], "overlaps");
+    assert_invalid(&["example.com:8080", "s3.example.com"], "overlaps");
+}
+
+#[test]
+fn empty_proxy_hosts_are_rejected() {
+    assert_invalid(&[], "should not be empty");
+}
+
+#[test]
+fn invalid_dns_labels_are_rejected() {
+    assert_invalid(&["s3..example.com"], "label \"\"");
+    assert_invalid(&["-s3.example.com"], "should not start or end with '-'");
+    assert_invalid(&["s3_proxy.example.com"], "letters, digits and '-'");
+    assert_invalid(&[&format!("{}.com", "a".repeat(64))], "1 to 63 characters");
+    assert_invalid(&["s3.example.com:http"], "port \"http\" not valid");
+}
+
+#[test]
+fn config_with_invalid_proxy_hosts_is_refused() {
+    let mut proxy_hosts = HostDomains::default();
+    proxy_hosts.domains = hosts(&["proxy.example.com", "s3-proxy.example.com"]);
+    let config = S3Config {
+        proxy_hosts,
+        ..Default::default()
+    };
+    assert!(S3Config::new_from(config).is_err());
+}
+
+#[test]
+fn changed_sinks_are_reported_unapplied() {
+    let config = S3Config::default();
+    assert!(config.unapplied_changes(&S3Config::default()).is_empty());
+
+    let changed = S3Config {
+        access_log: Some(AccessLogConfig {
+            path: "access.log".to_string(),
+            max_size_mb: 100,
+            max_files: 10,
+        }),
+        tracing: Some(TracingConfig {
+            otlp_endpoint: "http://otel-collector:4317".to_string(),
+            service_name: "s3-proxy".to_string(),
+            sample_ratio: 1.0,
+        }),
+        audit_log: Some(AuditLogConfig {
+            path: "audit.log".to_string(),
+        }),
+        ..Default::default()
+    };
+    assert_eq!(
+        config.unapplied_changes(&changed),
+        vec!["access_log", "tracing", "audit_log"]
+    );
+}
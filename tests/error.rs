@@ -0,0 +1,89 @@
+use http::Request;
+use hyper::Body;
+use piam_proxy::error::ProxyError;
+use s3_proxy::{error::S3ProxyError, request::S3RequestTransform, response::into_s3_response};
+
+const PROXY_HOST: &str = "s3-proxy.test";
+
+fn path_style_request(uri: &str) -> Request<Body> {
+    Request::get(uri)
+        .header("host", PROXY_HOST)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[test]
+fn path_style_rewrite() {
+    let mut req = path_style_request("/bucket/dir/key.txt?versionId=1");
+    req.adapt_path_style("bucket/dir/key.txt".into(), &[PROXY_HOST.into()])
+        .unwrap();
+
+    assert_eq!(req.uri().to_string(), "/dir/key.txt?versionId=1");
+    assert_eq!(req.headers()["host"], "bucket.s3-proxy.test");
+}
+
+#[test]
+fn path_style_mismatch_is_typed() {
+    let mut req = path_style_request("/other/key.txt");
+    let err = req
+        .adapt_path_style("bucket/key.txt".into(), &[PROXY_HOST.into()])
+        .unwrap_err();
+
+    assert!(matches!(err, S3ProxyError::PathStyle(_)));
+    assert_eq!(err.code(), "path_style");
+    assert!(matches!(
+        ProxyError::from(err),
+        ProxyError::MalformedProtocol(_)
+    ));
+}
+
+#[test]
+fn missing_host_is_typed() {
+    let mut req = Request::get("/bucket/key.txt").body(Body::empty()).unwrap();
+    let err = req
+        .adapt_path_style("bucket/key.txt".into(), &[PROXY_HOST.into()])
+        .unwrap_err();
+
+    assert_eq!(err.code(), "invalid_host");
+}
+
+async fn error_document(err: S3ProxyError) -> (u16, String) {
+    let res = into_s3_response(Err(err), "/bucket/key.txt");
+    let status = res.status().as_u16();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+fn element<'a>(body: &'a str, name: &str) -> &'a str {
+    body.split(&format!("<{name}>"))
+        .nth(1)
+        .and_then(|rest| rest.split(&format!("</{name}>")).next())
+        .unwrap()
+}
+
+async fn error_code(err: S3ProxyError) -> (u16, String) {
+    let (status, body) = error_document(err).await;
+    (status, element(&body, "Code").to_string())
+}
+
+#[tokio::test]
+async fn only_missing_buckets_are_no_such_bucket() {
+    assert_eq!(
+        error_code(S3ProxyError::BucketNotFound("bucket".into())).await,
+        (404, "NoSuchBucket".into())
+    );
+    let missing_user = ProxyError::ResourceNotFound("user not found".into());
+    assert_eq!(
+        error_code(missing_user.into()).await,
+        (403, "AccessDenied".into())
+    );
+}
+
+#[tokio::test]
+async fn denial_details_are_not_sent_to_clients() {
+    let missing_policy = ProxyError::OperationNotAllowed("no policy of group_test".into());
+    let (status, body) = error_document(missing_policy.into()).await;
+    assert_eq!(status, 403);
+    assert_eq!(element(&body, "Message"), "Access Denied");
+    assert!(!body.contains("group_test"));
+}
@@ -0,0 +1,103 @@
+use std::{fs, path::PathBuf, sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use piam_object_storage::config::HostDomains;
+use s3_proxy::{config::LOCAL_CONFIG_CHECK_INTERVAL, local::LocalSource, refresh, S3Config};
+
+const CORE_CONFIG: &str = include_str!("fixtures/core_config.yaml");
+
+fn config_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("s3-proxy-local-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("core_config.yaml"), CORE_CONFIG).unwrap();
+    dir
+}
+
+fn s3_config(domains: &[&str], admin_token: Option<&str>) -> S3Config {
+    let mut proxy_hosts = HostDomains::default();
+    proxy_hosts.domains = domains.iter().map(|domain| domain.to_string()).collect();
+    S3Config {
+        proxy_hosts,
+        admin_token: admin_token.map(String::from),
+        // nothing is listed from aws
+        #[cfg(feature = "uni-key")]
+        uni_key_static_buckets: Some(Default::default()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn state_is_loaded_from_a_local_directory() {
+    let dir = config_dir();
+    let config = s3_config(&["s3-proxy.local"], None);
+    fs::write(
+        dir.join("s3_config.yaml"),
+        serde_yaml::to_string(&config).unwrap(),
+    )
+    .unwrap();
+
+    let state = LocalSource::new(&dir).load().await.unwrap();
+    assert!(state.extended_config.user_count > 0);
+    assert_eq!(
+        state.extended_config.proxy_hosts.domains,
+        vec!["s3-proxy.local"]
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn refused_local_config_keeps_the_last_good_state() {
+    let dir = config_dir();
+    let source = LocalSource::new(&dir);
+    let config = s3_config(&["s3-proxy.local"], None);
+    fs::write(
+        dir.join("s3_config.yaml"),
+        serde_yaml::to_string(&config).unwrap(),
+    )
+    .unwrap();
+    let state = Arc::new(ArcSwap::from_pointee(source.load().await.unwrap()));
+
+    let overlapping = s3_config(&["proxy.local", "s3-proxy.local"], Some("refused"));
+    fs::write(
+        dir.join("s3_config.yaml"),
+        serde_yaml::to_string(&overlapping).unwrap(),
+    )
+    .unwrap();
+    assert!(!source.reload(&state).await);
+    assert_eq!(state.load().extended_config.admin_token, None);
+
+    // json is read as well
+    fs::remove_file(dir.join("s3_config.yaml")).unwrap();
+    let changed = s3_config(&["s3-proxy.local"], Some("accepted"));
+    fs::write(
+        dir.join("s3_config.json"),
+        serde_json::to_string(&changed).unwrap(),
+    )
+    .unwrap();
+    assert!(source.reload(&state).await);
+    assert_eq!(
+        state.load().extended_config.admin_token.as_deref(),
+        Some("accepted")
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn unchanged_local_config_does_not_get_stale() {
+    let dir = config_dir();
+    let source = LocalSource::new(&dir);
+    let config = s3_config(&["s3-proxy.local"], None);
+    fs::write(
+        dir.join("s3_config.yaml"),
+        serde_yaml::to_string(&config).unwrap(),
+    )
+    .unwrap();
+    let state = Arc::new(ArcSwap::from_pointee(source.load().await.unwrap()));
+    source.clone().watch(state);
+
+    tokio::time::sleep(Duration::from_secs(LOCAL_CONFIG_CHECK_INTERVAL + 2)).await;
+    let status = refresh::status();
+    assert!(status.age().unwrap() <= 2, "{status:?}");
+    assert!(!status.is_stale());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,646 @@
+#![cfg(not(feature = "uni-key"))]
+
+mod common;
+
+use std::time::Duration;
+
+use aws_sdk_s3::{
+    presigning::PresigningConfig,
+    primitives::ByteStream,
+    types::{Delete, ObjectIdentifier},
+};
+use common::*;
+use http::{Method, Request, StatusCode};
+use hyper::Body;
+use s3_proxy::{shadow::ShadowConfig, tls::TlsConfig};
+
+#[tokio::test]
+async fn path_style_put_and_get() {
+    let proxy = start().await;
+    let client = proxy.client(true);
+
+    client
+        .put_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("hello.txt")
+        .body(ByteStream::from_static(b"hello"))
+        .send()
+        .await
+        .unwrap();
+    let put = proxy.upstream.last_request();
+    assert_eq!(put.method, Method::PUT);
+    assert_eq!(put.uri.path(), "/hello.txt");
+    assert!(put
+        .host
+        .starts_with(&format!("{ALLOWED_BUCKET}.127.0.0.1:")));
+
+    let object = client
+        .get_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("hello.txt")
+        .send()
+        .await
+        .unwrap();
+    let body = object.body.collect().await.unwrap().into_bytes();
+    assert_eq!(body.as_ref(), b"hello");
+}
+
+#[tokio::test]
+async fn virtual_hosted_put_and_get() {
+    let proxy = start().await;
+    let client = proxy.client(false);
+
+    client
+        .put_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("dir/hello.txt")
+        .body(ByteStream::from_static(b"hello"))
+        .send()
+        .await
+        .unwrap();
+    let object = client
+        .get_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("dir/hello.txt")
+        .send()
+        .await
+        .unwrap();
+    let body = object.body.collect().await.unwrap().into_bytes();
+    assert_eq!(body.as_ref(), b"hello");
+
+    let get = proxy.upstream.last_request();
+    assert_eq!(get.method, Method::GET);
+    assert_eq!(get.uri.path(), "/dir/hello.txt");
+    assert!(get
+        .host
+        .starts_with(&format!("{ALLOWED_BUCKET}.127.0.0.1:")));
+}
+
+#[tokio::test]
+async fn list_objects() {
+    let proxy = start().await;
+    let client = proxy.client(true);
+
+    client
+        .put_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("a.txt")
+        .body(ByteStream::from_static(b"a"))
+        .send()
+        .await
+        .unwrap();
+    let listed = client
+        .list_objects_v2()
+        .bucket(ALLOWED_BUCKET)
+        .send()
+        .await
+        .unwrap();
+    let keys: Vec<_> = listed
+        .contents()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|o| o.key())
+        .collect();
+    assert_eq!(keys, ["a.txt"]);
+}
+
+#[tokio::test]
+async fn requests_are_resigned_with_account_credentials() {
+    let proxy = start().await;
+    proxy
+        .client(true)
+        .put_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("signed.txt")
+        .body(ByteStream::from_static(b"signed"))
+        .send()
+        .await
+        .unwrap();
+
+    let put = proxy.upstream.last_request();
+    let authorization = put.headers["authorization"].to_str().unwrap();
+    assert!(authorization.starts_with("AWS4-HMAC-SHA256"));
+    assert!(authorization.contains(&format!("Credential={ACCOUNT_ACCESS_KEY}/")));
+    assert!(!authorization.contains(USER_BASE_ACCESS_KEY));
+}
+
+#[tokio::test]
+async fn policy_denies_bucket_without_allow() {
+    let proxy = start().await;
+    let result = proxy
+        .client(true)
+        .get_object()
+        .bucket(DENIED_BUCKET)
+        .key("secret.txt")
+        .send()
+        .await;
+
+    assert!(result.is_err());
+    assert!(proxy.upstream.requests().is_empty());
+}
+
+#[tokio::test]
+async fn unknown_access_key_is_rejected() {
+    let proxy = start().await;
+    let result = proxy
+        .client_with_keys(&format!("AKPSNOBODY{ACCOUNT_CODE}"), USER_SECRET_KEY, true)
+        .get_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("hello.txt")
+        .send()
+        .await;
+
+    assert!(result.is_err());
+    assert!(proxy.upstream.requests().is_empty());
+}
+
+#[tokio::test]
+async fn wrong_secret_key_is_rejected() {
+    let proxy = start().await;
+    let err = proxy
+        .client_with_keys(
+            &format!("{USER_BASE_ACCESS_KEY}{ACCOUNT_CODE}"),
+            "not-the-secret",
+            true,
+        )
+        .get_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("hello.txt")
+        .send()
+        .await
+        .unwrap_err();
+
+    assert!(format!("{:?}", err).contains("SignatureDoesNotMatch"));
+    assert!(proxy.upstream.requests().is_empty());
+}
+
+#[tokio::test]
+async fn delete_objects() {
+    let proxy = start().await;
+    let client = proxy.client(true);
+    for key in ["a.txt", "b.txt"] {
+        client
+            .put_object()
+            .bucket(ALLOWED_BUCKET)
+            .key(key)
+            .body(ByteStream::from_static(b"x"))
+            .send()
+            .await
+            .unwrap();
+    }
+
+    let delete = Delete::builder()
+        .objects(ObjectIdentifier::builder().key("a.txt").build())
+        .objects(ObjectIdentifier::builder().key("b.txt").build())
+        .build();
+    let deleted = client
+        .delete_objects()
+        .bucket(ALLOWED_BUCKET)
+        .delete(delete)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(deleted.deleted().unwrap_or_default().len(), 2);
+
+    let post = proxy.upstream.last_request();
+    assert_eq!(post.method, Method::POST);
+    assert_eq!(post.uri.query(), Some("delete"));
+    let body = std::str::from_utf8(&post.body).unwrap();
+    assert!(body.contains("<Key>a.txt</Key>") && body.contains("<Key>b.txt</Key>"));
+}
+
+#[tokio::test]
+async fn presigned_put_and_get() {
+    let proxy = start().await;
+    let client = proxy.client(true);
+    let expires = || PresigningConfig::expires_in(Duration::from_secs(300)).unwrap();
+
+    let put = client
+        .put_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("presigned.txt")
+        .presigned(expires())
+        .await
+        .unwrap();
+    let res = http_client()
+        .request(
+            Request::put(put.uri().to_string())
+                .body(Body::from("presigned"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let upstream = proxy.upstream.last_request();
+    assert_eq!(upstream.uri.query(), None);
+    let authorization = upstream.headers["authorization"].to_str().unwrap();
+    assert!(authorization.contains(&format!("Credential={ACCOUNT_ACCESS_KEY}/")));
+
+    let get = client
+        .get_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("presigned.txt")
+        .presigned(expires())
+        .await
+        .unwrap();
+    let res = http_client()
+        .get(get.uri().to_string().parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert_eq!(body.as_ref(), b"presigned");
+}
+
+#[tokio::test]
+async fn tampered_presigned_url_is_rejected() {
+    let proxy = start().await;
+    let get = proxy
+        .client(true)
+        .get_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("presigned.txt")
+        .presigned(PresigningConfig::expires_in(Duration::from_secs(300)).unwrap())
+        .await
+        .unwrap();
+    let tampered = get.uri().to_string().replace("presigned.txt", "other.txt");
+    let res = http_client().get(tampered.parse().unwrap()).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert!(proxy.upstream.requests().is_empty());
+}
+
+async fn issue_presigned_url(proxy: &TestProxy, bucket: &str, key: &str) -> hyper::Response<Body> {
+    let mut req = Request::get(format!(
+        "http://{}/_piam_presign?bucket={bucket}&key={key}&expires=60",
+        proxy.host()
+    ))
+    .header("host", proxy.host())
+    .body(Body::empty())
+    .unwrap();
+    sign_as_user(&mut req);
+    http_client().request(req).await.unwrap()
+}
+
+#[tokio::test]
+async fn issued_presigned_url_can_be_used() {
+    let proxy = start().await;
+    proxy
+        .client(true)
+        .put_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("issued.txt")
+        .body(ByteStream::from_static(b"issued"))
+        .send()
+        .await
+        .unwrap();
+
+    let res = issue_presigned_url(&proxy, ALLOWED_BUCKET, "issued.txt").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let issued: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let url = issued["url"].as_str().unwrap();
+    assert!(url.starts_with(&format!(
+        "http://{}/{ALLOWED_BUCKET}/issued.txt?",
+        proxy.host()
+    )));
+
+    let res = http_client().get(url.parse().unwrap()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert_eq!(body.as_ref(), b"issued");
+}
+
+#[tokio::test]
+async fn presigned_url_is_https_with_tls_configured() {
+    let proxy = start_with(|config| {
+        config.tls = Some(TlsConfig {
+            cert_path: "cert.pem".to_string(),
+            key_path: "key.pem".to_string(),
+            port: 443,
+            http: false,
+        })
+    })
+    .await;
+
+    let res = issue_presigned_url(&proxy, ALLOWED_BUCKET, "issued.txt").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let issued: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(issued["url"]
+        .as_str()
+        .unwrap()
+        .starts_with(&format!("https://{}/", proxy.host())));
+}
+
+#[tokio::test]
+async fn forwarded_proto_is_honored_from_trusted_proxies_only() {
+    let issued_url = |proxy: TestProxy| async move {
+        let mut req = Request::get(format!(
+            "http://{}/_piam_presign?bucket={ALLOWED_BUCKET}&key=issued.txt&expires=60",
+            proxy.host()
+        ))
+        .header("host", proxy.host())
+        .header("x-forwarded-proto", "https")
+        .body(Body::empty())
+        .unwrap();
+        sign_as_user(&mut req);
+        let res = http_client().request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let issued: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        issued["url"].as_str().unwrap().to_string()
+    };
+
+    let url = issued_url(start().await).await;
+    assert!(url.starts_with("http://"), "{url}");
+    let trusting = start_with(|config| config.trusted_proxies = vec![[127, 0, 0, 1].into()]).await;
+    let url = issued_url(trusting).await;
+    assert!(url.starts_with("https://"), "{url}");
+}
+
+#[tokio::test]
+async fn presigned_url_is_not_issued_without_policy() {
+    let proxy = start().await;
+    let res = issue_presigned_url(&proxy, DENIED_BUCKET, "secret.txt").await;
+    assert_ne!(res.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn denied_request_gets_s3_error_document() {
+    let proxy = start().await;
+    let mut req = Request::get(format!(
+        "http://{}/{DENIED_BUCKET}/secret.txt",
+        proxy.host()
+    ))
+    .header("host", proxy.host())
+    .body(Body::empty())
+    .unwrap();
+    sign_as_user(&mut req);
+    let res = http_client().request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let request_id = res.headers()["x-amz-request-id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains("<Code>AccessDenied</Code>"));
+    assert!(body.contains(&format!("<Resource>/{DENIED_BUCKET}/secret.txt</Resource>")));
+    assert!(body.contains(&format!("<RequestId>{request_id}</RequestId>")));
+    assert!(proxy.upstream.requests().is_empty());
+}
+
+#[tokio::test]
+async fn metrics_count_requests_by_decision() {
+    let proxy = start().await;
+    let _ = proxy
+        .client(true)
+        .get_object()
+        .bucket(DENIED_BUCKET)
+        .key("secret.txt")
+        .send()
+        .await;
+
+    let is_denied_count = |line: &&str| {
+        line.starts_with("s3_proxy_requests_total{")
+            && line.contains("decision=\"deny\"")
+            && line.contains("status=\"403\"")
+    };
+    // requests are counted once their response body is dropped
+    let mut counted = false;
+    for _ in 0..20 {
+        let res = http_client()
+            .get(
+                format!("http://{}/_piam_metrics", proxy.host())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        counted = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .any(|line| is_denied_count(&line));
+        if counted {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(counted);
+}
+
+#[tokio::test]
+async fn bucket_named_metrics_is_not_served_the_metrics() {
+    let proxy = start().await;
+    let res = http_client()
+        .get(format!("http://{}/metrics", proxy.host()).parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains("<Code>AccessDenied</Code>"), "{body}");
+    assert!(!body.contains("s3_proxy_"));
+}
+
+#[tokio::test]
+async fn shadow_policies_are_evaluated_but_not_enforced() {
+    let proxy = start_with(|config| {
+        config.shadow = Some(ShadowConfig {
+            core_config_path: concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/candidate_core_config.yaml"
+            )
+            .to_string(),
+        });
+    })
+    .await;
+    let result = proxy
+        .client(true)
+        .get_object()
+        .bucket(DENIED_BUCKET)
+        .key("secret.txt")
+        .send()
+        .await;
+    assert!(result.is_err());
+    assert!(proxy.upstream.requests().is_empty());
+
+    let res = http_client()
+        .get(
+            format!("http://{}/_piam_metrics", proxy.host())
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert!(std::str::from_utf8(&body)
+        .unwrap()
+        .contains("s3_proxy_shadow_decisions_total{live=\"deny\",shadow=\"allow\"}"));
+}
+
+fn manage_request(proxy: &TestProxy, method: Method, path_and_query: &str) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(format!("http://{}{path_and_query}", proxy.host()))
+        .header("authorization", format!("Bearer {ADMIN_TOKEN}"))
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn start_with_admin_token() -> TestProxy {
+    start_with(|config| config.admin_token = Some(ADMIN_TOKEN.to_string())).await
+}
+
+fn explain_path(bucket: &str) -> String {
+    format!(
+        "/_piam_explain?access_key={USER_BASE_ACCESS_KEY}{ACCOUNT_CODE}\
+         &bucket={bucket}&key=a.txt&action=GetObject&source_ip=10.0.0.1"
+    )
+}
+
+async fn explain(proxy: &TestProxy, bucket: &str) -> serde_json::Value {
+    let req = manage_request(proxy, Method::GET, &explain_path(bucket));
+    let res = http_client().request(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn explain_policy_decisions() {
+    let proxy = start_with_admin_token().await;
+
+    let allowed = explain(&proxy, ALLOWED_BUCKET).await;
+    assert_eq!(allowed["decision"], "allow");
+    assert_eq!(allowed["user"]["name"], "tester");
+    assert_eq!(allowed["groups"][0]["name"], "testers");
+    assert_eq!(allowed["account"], ACCOUNT_CODE);
+    assert_eq!(allowed["action"], "GetObject");
+    assert_eq!(
+        allowed["matched_policies"],
+        serde_json::json!(["policy_allowed_bucket"])
+    );
+
+    let denied = explain(&proxy, DENIED_BUCKET).await;
+    assert_eq!(denied["decision"], "deny");
+    assert!(denied["reason"].is_string());
+    assert!(proxy.upstream.requests().is_empty());
+}
+
+#[tokio::test]
+async fn explain_requires_admin_token() {
+    let proxy = start_with_admin_token().await;
+    let uri = format!("http://{}{}", proxy.host(), explain_path(ALLOWED_BUCKET));
+    let res = http_client().get(uri.parse().unwrap()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn manage_api_requires_admin_token() {
+    let proxy = start_with_admin_token().await;
+    for token in [None, Some("wrong-token")] {
+        let mut req = Request::get(format!("http://{}/_piam_manage_api/config", proxy.host()));
+        if let Some(token) = token {
+            req = req.header("authorization", format!("Bearer {token}"));
+        }
+        let res = http_client()
+            .request(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[tokio::test]
+async fn manage_api_dumps_config_without_secrets() {
+    let proxy = start_with_admin_token().await;
+    let req = manage_request(&proxy, Method::GET, "/_piam_manage_api/config");
+    let res = http_client().request(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let config: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(config["admin_token"], "<redacted>");
+    assert_eq!(config["proxy_hosts"]["domains"][0], proxy.host());
+    assert!(!std::str::from_utf8(&body).unwrap().contains(ADMIN_TOKEN));
+}
+
+#[tokio::test]
+async fn debug_mode_without_log_handle_is_refused() {
+    let proxy = start_with_admin_token().await;
+    let req = manage_request(&proxy, Method::PUT, "/_piam_manage_api?debug=on");
+    let res = http_client().request(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn manage_api_toggles_debug_of_a_user() {
+    let proxy = start_with_admin_token().await;
+    let path = "/_piam_manage_api/debug_user?user_id=user_test&on=true";
+    let req = manage_request(&proxy, Method::PUT, path);
+    let res = http_client().request(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert!(!std::str::from_utf8(&body)
+        .unwrap()
+        .contains(USER_BASE_ACCESS_KEY));
+    let debug: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(debug["debug_users"]
+        .as_array()
+        .unwrap()
+        .contains(&"user_test".into()));
+    assert!(s3_proxy::manage::is_debug_user("user_test"));
+}
+
+#[tokio::test]
+async fn health_reports_a_fresh_state_ok() {
+    let proxy = start().await;
+    s3_proxy::refresh::record_load();
+    let uri = format!("http://{}/health", proxy.host());
+    let res = http_client().get(uri.parse().unwrap()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"OK");
+}
+
+#[tokio::test]
+async fn livez_and_readyz_answer_once_the_state_is_loaded() {
+    let proxy = start_with(|config| config.probe_upstream = true).await;
+    for path in ["_piam_livez", "_piam_readyz"] {
+        let uri = format!("http://{}/{path}", proxy.host());
+        let res = http_client().get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK, "{path}");
+    }
+}
+
+#[tokio::test]
+async fn readyz_fails_with_an_unreachable_upstream() {
+    let closed_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let proxy = start_with(|config| {
+        config.probe_upstream = true;
+        config
+            .upstream_hosts
+            .insert(REGION.to_string(), format!("127.0.0.1:{closed_port}"));
+    })
+    .await;
+    let uri = format!("http://{}/_piam_readyz", proxy.host());
+    let res = http_client().get(uri.parse().unwrap()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let readiness: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(readiness["ready"], false);
+    assert_eq!(readiness["checks"]["users"], "ok");
+    assert!(readiness["checks"]["upstream"]
+        .as_str()
+        .unwrap()
+        .contains("unreachable"));
+}
@@ -0,0 +1,47 @@
+#![cfg(not(feature = "uni-key"))]
+
+mod common;
+
+use common::*;
+use s3_proxy::shadow::ShadowConfig;
+
+#[tokio::test]
+async fn candidate_is_evaluated_when_no_live_policy_is_found() {
+    let proxy = start_with(|config| {
+        config.shadow = Some(ShadowConfig {
+            core_config_path: concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/candidate_core_config.yaml"
+            )
+            .to_string(),
+        });
+    })
+    .await;
+    let result = proxy
+        .client_in_region(
+            &format!("{USER_BASE_ACCESS_KEY}{ACCOUNT_CODE}"),
+            USER_SECRET_KEY,
+            true,
+            CANDIDATE_ONLY_REGION,
+        )
+        .get_object()
+        .bucket(ALLOWED_BUCKET)
+        .key("a.txt")
+        .send()
+        .await;
+    assert!(result.is_err());
+    assert!(proxy.upstream.requests().is_empty());
+
+    let res = http_client()
+        .get(
+            format!("http://{}/_piam_metrics", proxy.host())
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert!(std::str::from_utf8(&body)
+        .unwrap()
+        .contains("s3_proxy_shadow_decisions_total{live=\"deny\",shadow=\"allow\"} 1"));
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::*;
+use http::{Request, StatusCode};
+use hyper::Body;
+use s3_proxy::{access_log::AccessRecord, shutdown};
+
+#[tokio::test]
+async fn draining_fails_health_and_waits_for_requests_in_flight() {
+    let proxy = start().await;
+    let req = Request::get(format!("/{ALLOWED_BUCKET}/large.bin"))
+        .body(Body::empty())
+        .unwrap();
+    let record = AccessRecord::start(&req, "127.0.0.1:40000".parse().unwrap());
+    assert_eq!(shutdown::in_flight(), 1);
+    // internal endpoints serving requests are counted as well
+    let req = Request::get("/_piam_presign?method=GET")
+        .body(Body::empty())
+        .unwrap();
+    let presign = shutdown::track_request(&req, "127.0.0.1:40001".parse().unwrap());
+    assert_eq!(shutdown::in_flight(), 2);
+
+    shutdown::begin();
+    let uri = format!("http://{}/health", proxy.host());
+    let res = http_client().get(uri.parse().unwrap()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // the servers never finish draining, the deadline ends the shutdown
+    let started = Instant::now();
+    shutdown::drain(std::future::pending(), Duration::from_millis(100)).await;
+    assert!(started.elapsed() >= Duration::from_millis(100));
+
+    drop(record);
+    drop(presign);
+    assert_eq!(shutdown::in_flight(), 0);
+    // drained servers end the shutdown before the deadline
+    let started = Instant::now();
+    shutdown::drain(async {}, Duration::from_secs(60)).await;
+    assert!(started.elapsed() < Duration::from_secs(60));
+}
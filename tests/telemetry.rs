@@ -0,0 +1,49 @@
+#![cfg(not(feature = "uni-key"))]
+
+mod common;
+
+use common::*;
+use http::{Request, StatusCode};
+use hyper::Body;
+use opentelemetry::{
+    global,
+    sdk::{propagation::TraceContextPropagator, trace::TracerProvider},
+    trace::TracerProvider as _,
+};
+use tracing_subscriber::layer::SubscriberExt;
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const CLIENT_SPAN_ID: &str = "00f067aa0ba902b7";
+
+#[tokio::test]
+async fn traceparent_is_propagated_upstream() {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = TracerProvider::builder().build().tracer("test");
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber).unwrap();
+
+    let proxy = start().await;
+    let mut req = Request::put(format!(
+        "http://{}/{ALLOWED_BUCKET}/traced.txt",
+        proxy.host()
+    ))
+    .header("host", proxy.host())
+    .body(Body::empty())
+    .unwrap();
+    sign_as_user(&mut req);
+    req.headers_mut().insert(
+        "traceparent",
+        format!("00-{TRACE_ID}-{CLIENT_SPAN_ID}-01")
+            .parse()
+            .unwrap(),
+    );
+    let res = http_client().request(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let upstream = proxy.upstream.last_request();
+    let traceparent = upstream.headers["traceparent"].to_str().unwrap();
+    let parts: Vec<_> = traceparent.split('-').collect();
+    assert_eq!(parts[1], TRACE_ID);
+    assert_ne!(parts[2], CLIENT_SPAN_ID);
+}
@@ -0,0 +1,358 @@
+#![cfg(feature = "uni-key")]
+
+mod common;
+
+use std::{
+    collections::HashMap,
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{extract::State, Router};
+use common::{
+    http_client, start_with, ADMIN_TOKEN, ALLOWED_BUCKET, DENIED_BUCKET, PROXY_HOST,
+    USER_BASE_ACCESS_KEY, USER_SECRET_KEY,
+};
+use http::{header::HOST, Method, Request, Response, StatusCode};
+use hyper::Body;
+use piam_core::account::aws::AwsAccount;
+use piam_object_storage::{config::HostDomains, input::ObjectStorageInput};
+use piam_proxy::error::ProxyError;
+use s3_proxy::{
+    audit::AuditLogConfig,
+    error::S3ProxyError,
+    uni_key::{AccountRoute, Provider, UniKeyInfo},
+};
+
+const LISTED_BUCKET: &str = "listed-bucket";
+const CREATED_BUCKET: &str = "created-bucket";
+const FOREIGN_BUCKET: &str = "foreign-bucket";
+
+fn route(account: &str, region: &str) -> AccountRoute {
+    AccountRoute {
+        account: account.to_string(),
+        region: region.to_string(),
+        endpoint: None,
+        provider: Provider::Aws,
+    }
+}
+
+fn accounts(ids: &[(&str, &str)]) -> Vec<AwsAccount> {
+    ids.iter()
+        .map(|(id, code)| {
+            serde_yaml::from_str(&format!(
+                "{{id: {id}, code: \"{code}\", access_key: AKIA{code}, secret_key: secret-{code}}}"
+            ))
+            .unwrap()
+        })
+        .collect()
+}
+
+fn regions_of(uni_key_info: &UniKeyInfo) -> HashMap<String, String> {
+    uni_key_info
+        .buckets()
+        .into_iter()
+        .map(|(bucket, access_info)| (bucket, access_info.region))
+        .collect()
+}
+
+#[test]
+fn exact_route_takes_precedence_over_the_longest_prefix() {
+    let routes = vec![
+        route("us_aws*", "us-east-1"),
+        route("us_aws_cas*", "us-west-2"),
+        route("us_aws_cas_1549", "us-east-2"),
+    ];
+    let region = |id| AccountRoute::find(&routes, id).map(|route| route.region.as_str());
+    assert_eq!(region("us_aws_cas_1549"), Some("us-east-2"));
+    assert_eq!(region("us_aws_cas_0001"), Some("us-west-2"));
+    assert_eq!(region("us_aws_0001"), Some("us-east-1"));
+    assert_eq!(region("cn_aws_0001"), None);
+}
+
+#[test]
+fn malformed_routes_are_rejected() {
+    let invalid = |routes: Vec<AccountRoute>| {
+        assert!(matches!(
+            AccountRoute::validate(&routes),
+            Err(ProxyError::AssertFail(_))
+        ))
+    };
+    invalid(vec![route("", "us-east-1")]);
+    invalid(vec![route("us_*_aws*", "us-east-1")]);
+    invalid(vec![route("us_aws*", "")]);
+    invalid(vec![
+        route("us_aws*", "us-east-1"),
+        route("us_aws*", "us-east-2"),
+    ]);
+    AccountRoute::validate(&[
+        route("us_aws*", "us-east-1"),
+        route("us_aws_1", "us-east-2"),
+    ])
+    .unwrap();
+}
+
+#[test]
+fn accounts_are_routed_by_the_defaults_without_routes() {
+    let accounts = accounts(&[("cn_aws_0001", "0001"), ("us_aws_cas_1549", "1549")]);
+    let buckets = HashMap::from([
+        ("0001".to_string(), vec!["cn-bucket".to_string()]),
+        ("1549".to_string(), vec!["cas-bucket".to_string()]),
+    ]);
+    let uni_key_info = UniKeyInfo::new_static(&accounts, &[], &buckets).unwrap();
+    let regions = regions_of(&uni_key_info);
+    assert_eq!(regions["cn-bucket"], "cn-northwest-1");
+    assert_eq!(regions["cas-bucket"], "us-east-2");
+}
+
+#[test]
+fn configured_routes_replace_the_defaults() {
+    let accounts = accounts(&[("cn_aws_0001", "0001")]);
+    let buckets = HashMap::from([("0001".to_string(), vec!["cn-bucket".to_string()])]);
+    let routes = [route("cn_aws*", "cn-north-1")];
+    let uni_key_info = UniKeyInfo::new_static(&accounts, &routes, &buckets).unwrap();
+    assert_eq!(regions_of(&uni_key_info)["cn-bucket"], "cn-north-1");
+
+    let routes = [route("us_aws*", "us-east-1")];
+    assert!(UniKeyInfo::new_static(&accounts, &routes, &buckets).is_err());
+}
+
+/// Account endpoint serving HeadBucket and ListBuckets, counting the HeadBucket requests.
+/// [`FOREIGN_BUCKET`] answers HeadBucket but is never listed, like a public bucket of another
+/// account.
+#[derive(Clone, Default)]
+struct FakeAccount {
+    head_buckets: Arc<AtomicUsize>,
+    listed: Arc<Mutex<Vec<String>>>,
+}
+
+async fn serve_account(fake: FakeAccount) -> String {
+    async fn account(State(fake): State<FakeAccount>, req: Request<Body>) -> Response<Body> {
+        let path = req.uri().path().trim_start_matches('/').to_string();
+        let listed = fake.listed.lock().unwrap().clone();
+        match *req.method() {
+            Method::GET if path.is_empty() => {
+                let buckets: String = listed
+                    .iter()
+                    .map(|bucket| format!("<Bucket><Name>{bucket}</Name></Bucket>"))
+                    .collect();
+                Response::builder()
+                    .header("Content-Type", "application/xml")
+                    .body(Body::from(format!(
+                        "<ListAllMyBucketsResult><Buckets>{buckets}</Buckets>\
+                         </ListAllMyBucketsResult>"
+                    )))
+                    .unwrap()
+            }
+            Method::HEAD => {
+                fake.head_buckets.fetch_add(1, Ordering::SeqCst);
+                let status = if listed.contains(&path) || path == FOREIGN_BUCKET {
+                    StatusCode::OK
+                } else {
+                    StatusCode::NOT_FOUND
+                };
+                Response::builder()
+                    .status(status)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            _ => Response::builder()
+                .status(StatusCode::NOT_IMPLEMENTED)
+                .body(Body::empty())
+                .unwrap(),
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let app = Router::new().fallback(account).with_state(fake);
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service()),
+    );
+    endpoint
+}
+
+async fn input_of(bucket: &str) -> ObjectStorageInput {
+    let mut proxy_hosts = HostDomains::default();
+    proxy_hosts.domains = vec![PROXY_HOST.to_string()];
+    let req = Request::builder()
+        .method(Method::HEAD)
+        .uri("/")
+        .header(HOST, format!("{bucket}.{PROXY_HOST}"))
+        .body(Body::empty())
+        .unwrap();
+    let (input, _) = ObjectStorageInput::parse(req, &proxy_hosts)
+        .await
+        .unwrap()
+        .into_parts();
+    input
+}
+
+#[tokio::test]
+async fn missing_bucket_is_looked_up_on_the_accounts() {
+    let fake = FakeAccount::default();
+    fake.listed.lock().unwrap().push(LISTED_BUCKET.to_string());
+    let endpoint = serve_account(fake.clone()).await;
+    let routes = [AccountRoute {
+        endpoint: Some(endpoint),
+        ..route("us_aws*", "us-east-1")
+    }];
+    let accounts = accounts(&[("us_aws_0001", "0001")]);
+    let uni_key_info = UniKeyInfo::new_from(&accounts, &routes, Duration::from_secs(3600))
+        .await
+        .unwrap();
+    assert_eq!(regions_of(&uni_key_info).len(), 1);
+
+    // created after the listing
+    fake.listed.lock().unwrap().push(CREATED_BUCKET.to_string());
+    let access_info = uni_key_info
+        .find_access_info(&input_of(CREATED_BUCKET).await, "us-east-1")
+        .await
+        .unwrap();
+    assert_eq!(access_info.account.code, "0001");
+    assert!(regions_of(&uni_key_info).contains_key(CREATED_BUCKET));
+    assert_eq!(fake.head_buckets.load(Ordering::SeqCst), 1);
+
+    // answering HeadBucket without listing the bucket does not make the account its owner,
+    // and a bucket found nowhere is remembered, the accounts are not asked again
+    for bucket in [FOREIGN_BUCKET, "unknown-bucket"] {
+        for _ in 0..2 {
+            let result = uni_key_info
+                .find_access_info(&input_of(bucket).await, "us-east-1")
+                .await;
+            assert!(matches!(result, Err(S3ProxyError::BucketNotFound(_))));
+        }
+    }
+    assert!(!regions_of(&uni_key_info).contains_key(FOREIGN_BUCKET));
+    assert_eq!(fake.head_buckets.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn failed_listing_is_reported_without_details() {
+    // nothing listens on the endpoint once the listener is dropped
+    let endpoint = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+    let routes = [AccountRoute {
+        endpoint: Some(endpoint.clone()),
+        ..route("us_aws*", "us-east-1")
+    }];
+    let accounts = accounts(&[("us_aws_0002", "0002")]);
+    let uni_key_info = UniKeyInfo::new_from(&accounts, &routes, Duration::from_secs(3600))
+        .await
+        .unwrap();
+
+    let statuses = uni_key_info.statuses();
+    assert_eq!(statuses.len(), 1);
+    let status = &statuses[0];
+    assert_eq!(status.account, "0002");
+    assert!(status.degraded);
+    assert_eq!(status.error.as_deref(), Some("list_buckets_failed"));
+    assert_eq!(status.buckets, 0);
+    let output = serde_json::to_string(&statuses).unwrap();
+    assert!(!output.contains("AKIA0002") && !output.contains(&endpoint));
+}
+
+#[tokio::test]
+async fn status_of_the_accounts_is_served_to_admins_only() {
+    let proxy = start_with(|config| {
+        config.admin_token = Some(ADMIN_TOKEN.to_string());
+        config.uni_key_routes = vec![route("us_aws*", "us-east-1")];
+        config.uni_key_static_buckets = Some(HashMap::from([(
+            "0001".to_string(),
+            vec![ALLOWED_BUCKET.to_string()],
+        )]));
+    })
+    .await;
+    let status_request = |token: Option<&str>| {
+        let builder =
+            Request::builder().uri(format!("http://{}/_piam_uni_key_status", proxy.host()));
+        let builder = match token {
+            Some(token) => builder.header("authorization", format!("Bearer {token}")),
+            None => builder,
+        };
+        builder.body(Body::empty()).unwrap()
+    };
+
+    for token in [None, Some("wrong-token")] {
+        let res = http_client().request(status_request(token)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let res = http_client()
+        .request(status_request(Some(ADMIN_TOKEN)))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let statuses: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        statuses,
+        serde_json::json!([{
+            "account": "0001",
+            "region": "us-east-1",
+            "degraded": false,
+            "error": null,
+            "last_success": statuses[0]["last_success"],
+            "buckets": 1,
+        }])
+    );
+    assert!(statuses[0]["last_success"].is_u64());
+}
+
+#[tokio::test]
+async fn list_buckets_is_audited_without_its_probes() {
+    let path = std::env::temp_dir().join(format!("s3-proxy-audit-{}.log", uuid::Uuid::new_v4()));
+    let path = path.display().to_string();
+    AuditLogConfig { path: path.clone() }.init().unwrap();
+    let proxy = start_with(|config| {
+        config.uni_key_routes = vec![route("us_aws*", "us-east-1")];
+        config.uni_key_static_buckets = Some(HashMap::from([(
+            "0001".to_string(),
+            vec![ALLOWED_BUCKET.to_string(), DENIED_BUCKET.to_string()],
+        )]));
+    })
+    .await;
+
+    let listed = proxy
+        .client_with_keys(USER_BASE_ACCESS_KEY, USER_SECRET_KEY, true)
+        .list_buckets()
+        .send()
+        .await
+        .unwrap();
+    let names: Vec<_> = listed
+        .buckets()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|bucket| bucket.name())
+        .collect();
+    assert_eq!(names, vec![ALLOWED_BUCKET]);
+    let owner = listed.owner().unwrap();
+    assert_eq!(owner.id(), Some("user_test"));
+    assert_eq!(owner.display_name(), Some("tester"));
+
+    let mut events = vec![];
+    for _ in 0..50 {
+        let content = std::fs::read_to_string(&path).unwrap_or_default();
+        events = content
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect();
+        if !events.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["action"], "ListBuckets");
+    assert_eq!(events[0]["access_key"], USER_BASE_ACCESS_KEY);
+    assert_eq!(events[0]["decision"], "allow");
+    assert_eq!(events[0]["reason"], "1 buckets visible");
+}